@@ -0,0 +1,31 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use oversync_lib::engine::chunker;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut data = vec![0u8; len];
+    StdRng::seed_from_u64(0).fill_bytes(&mut data);
+    data
+}
+
+fn chunking(c: &mut Criterion) {
+    let mut group = c.benchmark_group("chunking");
+    for size in [1024 * 1024, 64 * 1024 * 1024] {
+        let data = random_bytes(size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("split", size), &data, |b, data| {
+            b.iter(|| chunker::chunks(black_box(data)).count())
+        });
+        group.bench_with_input(BenchmarkId::new("split_and_hash", size), &data, |b, data| {
+            b.iter(|| {
+                for chunk in chunker::chunks(black_box(data)) {
+                    black_box(blake3::hash(chunk));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, chunking);
+criterion_main!(benches);
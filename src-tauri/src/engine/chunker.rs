@@ -0,0 +1,174 @@
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Content-defined chunking (FastCDC with normalized chunking), so an edit
+/// in the middle of a large file only changes the chunks around it.
+///
+/// Chunks are never smaller than `MIN_CHUNK_SIZE` (except a file's last),
+/// never larger than `MAX_CHUNK_SIZE`, and average about `AVG_CHUNK_SIZE`.
+pub const MIN_CHUNK_SIZE: usize = 64 * 1024;
+pub const AVG_CHUNK_SIZE: usize = 256 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+const AVG_BITS: u32 = AVG_CHUNK_SIZE.trailing_zeros();
+
+/// Harder to match below the average size, easier above it, which keeps
+/// chunk sizes close to the average.
+const MASK_SMALL: u64 = mask(AVG_BITS + 2);
+const MASK_LARGE: u64 = mask(AVG_BITS - 2);
+
+/// The rolling hash is shifted left, so its high bits depend on the most
+/// bytes; those are the ones tested.
+const fn mask(bits: u32) -> u64 {
+    ((1u64 << bits) - 1) << (64 - bits)
+}
+
+/// One pseudo-random value per byte, from splitmix64 so the table is
+/// fixed across builds and devices.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state = 0x6f76_6572_7379_6e63u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Length of the chunk at the start of `data`.
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let normal = end.min(AVG_CHUNK_SIZE);
+
+    let mut hash = 0u64;
+    for (i, byte) in data[..normal].iter().enumerate().skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        if hash & MASK_SMALL == 0 {
+            return i + 1;
+        }
+    }
+    for (i, byte) in data[..end].iter().enumerate().skip(normal) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        if hash & MASK_LARGE == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// Split `data` into content-defined chunks, in order. Empty input has no
+/// chunks.
+pub fn chunks(data: &[u8]) -> Chunks<'_> {
+    Chunks { rest: data }
+}
+
+pub struct Chunks<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let (chunk, rest) = self.rest.split_at(cut_point(self.rest));
+        self.rest = rest;
+        Some(chunk)
+    }
+}
+
+/// The same chunks as [`chunks`], read from `reader` as they are needed,
+/// so no more than `MAX_CHUNK_SIZE` of the input is held at once.
+pub struct ChunkReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: AsyncRead + Unpin> ChunkReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::with_capacity(MAX_CHUNK_SIZE),
+            eof: false,
+        }
+    }
+
+    /// The next chunk, or `None` once the input is exhausted.
+    pub async fn next_chunk(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        // A cut point is only final once a full chunk's worth, or the end
+        // of the input, is buffered.
+        while !self.eof && self.buf.len() < MAX_CHUNK_SIZE {
+            let filled = self.buf.len();
+            self.buf.resize(MAX_CHUNK_SIZE, 0);
+            let read = self.reader.read(&mut self.buf[filled..]).await?;
+            self.buf.truncate(filled + read);
+            self.eof = read == 0;
+        }
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let rest = self.buf.split_off(cut_point(&self.buf));
+        Ok(Some(std::mem::replace(&mut self.buf, rest)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chunks, ChunkReader, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+    use std::collections::HashSet;
+
+    fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        StdRng::seed_from_u64(seed).fill_bytes(&mut data);
+        data
+    }
+
+    #[test]
+    fn chunks_cover_input_within_size_bounds() {
+        let data = random_bytes(8 * 1024 * 1024, 1);
+        let parts: Vec<&[u8]> = chunks(&data).collect();
+
+        assert_eq!(parts.concat(), data);
+        for part in &parts[..parts.len() - 1] {
+            assert!(part.len() >= MIN_CHUNK_SIZE && part.len() <= MAX_CHUNK_SIZE);
+        }
+        assert!(chunks(&[]).next().is_none());
+    }
+
+    #[test]
+    fn insertion_only_changes_nearby_chunks() {
+        let data = random_bytes(8 * 1024 * 1024, 2);
+        let mut edited = data.clone();
+        edited.splice(3_000_000..3_000_000, *b"one more sentence");
+
+        let before: HashSet<&[u8]> = chunks(&data).collect();
+        let after: Vec<&[u8]> = chunks(&edited).collect();
+        let changed = after.iter().filter(|chunk| !before.contains(*chunk)).count();
+
+        assert!(changed <= 2, "{} of {} chunks changed", changed, after.len());
+    }
+
+    #[tokio::test]
+    async fn reader_chunks_like_a_slice() {
+        let data = random_bytes(5 * 1024 * 1024 + 123, 3);
+        let mut reader = ChunkReader::new(&data[..]);
+        let mut streamed = Vec::new();
+        while let Some(chunk) = reader.next_chunk().await.unwrap() {
+            streamed.push(chunk);
+        }
+
+        let expected: Vec<&[u8]> = chunks(&data).collect();
+        assert_eq!(streamed, expected);
+    }
+}
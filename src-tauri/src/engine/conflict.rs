@@ -0,0 +1,38 @@
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+/// What to do when a remote edit is concurrent with a local one.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictStrategy {
+    /// Keep the local file and write the remote one next to it as
+    /// `note (conflict from <device>).md`.
+    #[default]
+    KeepBoth,
+    LastWriterWins,
+    PreferLocal,
+    PreferRemote,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConflictRecord {
+    pub path: String,
+    pub peer: String,
+    pub detected_at: DateTime<Utc>,
+    pub strategy: ConflictStrategy,
+    /// Where the remote copy was written, for `KeepBoth`.
+    pub conflict_copy: Option<String>,
+}
+
+/// `folder/note.md` -> `folder/note (conflict from <device>).md`
+pub fn conflict_copy_path(path: &str, device: &str) -> String {
+    let (dir, name) = match path.rfind(['/', '\\']) {
+        Some(idx) => path.split_at(idx + 1),
+        None => ("", path),
+    };
+    let (stem, ext) = match name.rfind('.') {
+        Some(idx) if idx > 0 => name.split_at(idx),
+        _ => (name, ""),
+    };
+    format!("{}{} (conflict from {}){}", dir, stem, device, ext)
+}
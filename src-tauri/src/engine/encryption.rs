@@ -1,41 +1,667 @@
 use anyhow::{anyhow, Result};
 use chacha20poly1305::{
-    aead::{Aead, KeyInit},
+    aead::{Aead, KeyInit, Payload},
     XChaCha20Poly1305, XNonce,
 };
 use rand::{rngs::OsRng, RngCore};
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use zeroize::Zeroizing;
+use crate::engine::keys::KeyRing;
 
-pub struct Encryptor {
+/// How nonces are chosen.
+///
+/// `Random` gives unrelated ciphertexts for every encryption, so nothing
+/// about the plaintext leaks, but the same note sealed twice (on two saves
+/// or two devices) becomes two different blobs that can't be deduplicated.
+///
+/// `Convergent` derives the nonce from a hash of the plaintext keyed with
+/// the vault key, so identical content always seals to the identical blob
+/// and is stored and transferred once across devices, in iroh and on
+/// GitHub. The cost is that anyone who can see the ciphertexts learns which
+/// blobs hold equal content. Without the vault key they can't check a
+/// guessed plaintext against a blob; anyone holding the key can. Because a
+/// nonce only repeats for the same plaintext, the AEAD itself stays sound.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EncryptionMode {
+    #[default]
+    Random,
+    Convergent,
+}
+
+/// One key version: the cipher and the keys derived alongside it, all
+/// wiped from memory when dropped.
+struct EpochKey {
     cipher: XChaCha20Poly1305,
+    /// Keys the plaintext hash convergent nonces are taken from; separate
+    /// from the cipher key so the two uses never share a key.
+    nonce_key: Zeroizing<[u8; 32]>,
+    /// Keys the names content is stored under remotely.
+    name_key: Zeroizing<[u8; 32]>,
+}
+
+impl EpochKey {
+    fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(key.into()),
+            nonce_key: Zeroizing::new(blake3::derive_key("oversync convergent nonce", key)),
+            name_key: Zeroizing::new(blake3::derive_key("oversync github chunk names", key)),
+        }
+    }
+}
+
+struct KeySet {
+    current: u32,
+    keys: BTreeMap<u32, EpochKey>,
+}
+
+/// Encrypts under the newest key epoch and decrypts under any known one,
+/// so blobs sealed before a key rotation stay readable until re-encrypted.
+pub struct Encryptor {
+    mode: EncryptionMode,
+    keys: RwLock<KeySet>,
+}
+
+/// Start of every sealed blob.
+pub const MAGIC: &[u8; 4] = b"OVSY";
+const ENVELOPE_VERSION: u8 = 1;
+
+/// AEAD used for a blob, recorded in its envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Algorithm {
+    XChaCha20Poly1305 = 1,
+}
+
+/// magic, version, algorithm, epoch, nonce
+const HEADER_LEN: usize = 4 + 1 + 1 + 4 + 24;
+
+/// Envelope version of [`Encryptor::seal_stream`] output.
+const STREAM_VERSION: u8 = 2;
+
+/// Plaintext bytes per STREAM segment.
+pub const SEGMENT_LEN: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+/// Nonce prefix shared by a stream's segments; the rest of each nonce is a
+/// big-endian segment counter and a last-segment flag.
+const PREFIX_LEN: usize = 19;
+
+/// magic, version, algorithm, epoch, nonce prefix
+const STREAM_HEADER_LEN: usize = 4 + 1 + 1 + 4 + PREFIX_LEN;
+
+/// Sealed blobs are never smaller than this, so short notes all look alike.
+const MIN_PADDED_LEN: usize = 512;
+
+/// Where a blob belongs, authenticated along with it so a blob can't be
+/// passed off as another path's. Content-addressed blobs, whose plaintext
+/// hash is checked after decryption anyway, use [`Binding::NONE`].
+#[derive(Debug, Clone, Copy)]
+pub struct Binding<'a> {
+    pub path: &'a str,
+    pub file_id: &'a [u8],
+}
+
+impl Binding<'_> {
+    pub const NONE: Binding<'static> = Binding { path: "", file_id: b"" };
+
+    /// Header followed by the length-prefixed path and file id.
+    fn associated_data(&self, header: &[u8]) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(header.len() + 8 + self.path.len() + self.file_id.len());
+        aad.extend_from_slice(header);
+        aad.extend_from_slice(&(self.path.len() as u32).to_le_bytes());
+        aad.extend_from_slice(self.path.as_bytes());
+        aad.extend_from_slice(&(self.file_id.len() as u32).to_le_bytes());
+        aad.extend_from_slice(self.file_id);
+        aad
+    }
 }
 
 impl Encryptor {
     pub fn new(key: &[u8; 32]) -> Self {
-        let cipher = XChaCha20Poly1305::new(key.into());
-        Self { cipher }
+        Self::with_mode(key, EncryptionMode::Random)
     }
 
-    pub fn encrypt(&self, data: &[u8]) -> Result<(Vec<u8>, [u8; 24])> {
+    pub fn with_mode(key: &[u8; 32], mode: EncryptionMode) -> Self {
+        Self {
+            mode,
+            keys: RwLock::new(KeySet {
+                current: 0,
+                keys: BTreeMap::from([(0, EpochKey::new(key))]),
+            }),
+        }
+    }
+
+    /// An encryptor knowing every key in `ring`, encrypting under its newest.
+    pub fn with_ring(ring: &KeyRing, mode: EncryptionMode) -> Self {
+        let encryptor = Self::with_mode(ring.current().1.as_bytes(), mode);
+        {
+            let mut keys = encryptor.write_keys();
+            keys.keys.clear();
+            for (epoch, key) in ring.keys() {
+                keys.keys.insert(epoch, EpochKey::new(key.as_bytes()));
+            }
+            keys.current = ring.current().0;
+        }
+        encryptor
+    }
+
+    /// Start encrypting under `key` as `epoch`, keeping the older keys.
+    pub fn add_epoch(&self, epoch: u32, key: &[u8; 32]) {
+        let mut keys = self.write_keys();
+        keys.keys.insert(epoch, EpochKey::new(key));
+        keys.current = keys.current.max(epoch);
+    }
+
+    /// Keys are only ever added whole, so a panic while they were held
+    /// can't have left them inconsistent.
+    fn read_keys(&self) -> RwLockReadGuard<'_, KeySet> {
+        self.keys.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_keys(&self) -> RwLockWriteGuard<'_, KeySet> {
+        self.keys.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn current_epoch(&self) -> u32 {
+        self.read_keys().current
+    }
+
+    pub fn mode(&self) -> EncryptionMode {
+        self.mode
+    }
+
+    /// Key for naming content in a remote store, for the current epoch.
+    pub fn name_key(&self) -> Zeroizing<[u8; 32]> {
+        let keys = self.read_keys();
+        keys.keys[&keys.current].name_key.clone()
+    }
+
+    /// Convergent nonces cover the binding too, so equal content bound to
+    /// different paths never reuses a nonce with different associated data.
+    fn nonce_for(&self, key: &EpochKey, binding: &Binding, data: &[u8]) -> [u8; 24] {
         let mut nonce_bytes = [0u8; 24];
-        OsRng.fill_bytes(&mut nonce_bytes);
-        let nonce = XNonce::from_slice(&nonce_bytes);
+        match self.mode {
+            EncryptionMode::Random => OsRng.fill_bytes(&mut nonce_bytes),
+            EncryptionMode::Convergent => {
+                let mut hasher = blake3::Hasher::new_keyed(&key.nonce_key);
+                hasher.update(&binding.associated_data(b""));
+                hasher.update(data);
+                nonce_bytes.copy_from_slice(&hasher.finalize().as_bytes()[..24]);
+            }
+        }
+        nonce_bytes
+    }
 
-        let ciphertext = self
+    /// Encrypt `data` under the current epoch into an envelope: magic,
+    /// version, algorithm, epoch, nonce, ciphertext. The header and
+    /// `binding` are authenticated as associated data.
+    pub fn seal_bound(&self, data: &[u8], binding: Binding) -> Result<Vec<u8>> {
+        let keys = self.read_keys();
+        let key = &keys.keys[&keys.current];
+        let nonce = self.nonce_for(key, &binding, data);
+
+        let mut sealed = Vec::with_capacity(HEADER_LEN + data.len() + 16);
+        sealed.extend_from_slice(MAGIC);
+        sealed.push(ENVELOPE_VERSION);
+        sealed.push(Algorithm::XChaCha20Poly1305 as u8);
+        sealed.extend_from_slice(&keys.current.to_le_bytes());
+        sealed.extend_from_slice(&nonce);
+
+        let aad = binding.associated_data(&sealed);
+        let ciphertext = key
             .cipher
-            .encrypt(nonce, data)
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: data, aad: &aad })
             .map_err(|e| anyhow!("encryption failure: {}", e))?;
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
 
-        Ok((ciphertext, nonce_bytes))
+    /// Decrypt a blob from [`Encryptor::seal_bound`] or
+    /// [`Encryptor::seal_stream`] with the same binding.
+    pub fn open_bound(&self, sealed: &[u8], binding: Binding) -> Result<Vec<u8>> {
+        if !sealed.starts_with(MAGIC) {
+            return Err(anyhow!("not a sealed blob"));
+        }
+        if sealed.get(4) == Some(&STREAM_VERSION) {
+            return self.open_segments(sealed, binding);
+        }
+        if sealed.len() < HEADER_LEN {
+            return Err(anyhow!("sealed blob too short"));
+        }
+        let (header, ciphertext) = sealed.split_at(HEADER_LEN);
+        if header[4] != ENVELOPE_VERSION {
+            return Err(anyhow!("unsupported envelope version {}", header[4]));
+        }
+        if header[5] != Algorithm::XChaCha20Poly1305 as u8 {
+            return Err(anyhow!("unsupported algorithm {}", header[5]));
+        }
+        let epoch = u32::from_le_bytes(header[6..10].try_into()?);
+        let nonce = XNonce::from_slice(&header[10..]);
+
+        let keys = self.read_keys();
+        let key = keys
+            .keys
+            .get(&epoch)
+            .ok_or_else(|| anyhow!("no key for epoch {}", epoch))?;
+        let aad = binding.associated_data(header);
+        key.cipher
+            .decrypt(nonce, Payload { msg: ciphertext, aad: &aad })
+            .map_err(|e| anyhow!("decryption failure: {}", e))
     }
 
-    pub fn decrypt(&self, ciphertext: &[u8], nonce_bytes: &[u8; 24]) -> Result<Vec<u8>> {
-        let nonce = XNonce::from_slice(nonce_bytes);
+    /// Encrypt everything `reader` yields into `writer` as a STREAM envelope
+    /// (chunked AEAD): a header like [`Encryptor::seal_bound`]'s with a
+    /// nonce prefix, then `SEGMENT_LEN` segments each sealed under the
+    /// prefix, its index and whether it is the last. Only two segments are
+    /// held at a time, and reordered, dropped or truncated segments fail to
+    /// open. The plaintext is padded like [`pad`] does, so the stream's size
+    /// only reveals its bucket. Returns the number of plaintext bytes.
+    ///
+    /// In convergent mode the prefix is derived from `content_hash`, the
+    /// BLAKE3 hash of the plaintext; without one it is random. A wrong hash
+    /// would reuse another plaintext's nonces, so the sealed output is then
+    /// held in memory and only written once the plaintext has been checked
+    /// against it: convergent streams are meant for chunks, not whole files.
+    pub async fn seal_stream<R, W>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        binding: Binding<'_>,
+        content_hash: Option<&[u8; 32]>,
+    ) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        // Hasher, the hash it must come to, and the sealed output so far.
+        let mut held = None;
+        let (epoch, cipher, prefix) = {
+            let keys = self.read_keys();
+            let key = &keys.keys[&keys.current];
+            let mut prefix = [0u8; PREFIX_LEN];
+            match content_hash {
+                Some(hash) if self.mode == EncryptionMode::Convergent => {
+                    prefix.copy_from_slice(&self.nonce_for(key, &binding, hash)[..PREFIX_LEN]);
+                    held = Some((blake3::Hasher::new(), hash, Vec::new()));
+                }
+                _ => OsRng.fill_bytes(&mut prefix),
+            }
+            (keys.current, key.cipher.clone(), prefix)
+        };
 
-        let plaintext = self
-            .cipher
-            .decrypt(nonce, ciphertext)
-            .map_err(|e| anyhow!("decryption failure: {}", e))?;
+        let mut header = Vec::with_capacity(STREAM_HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.push(STREAM_VERSION);
+        header.push(Algorithm::XChaCha20Poly1305 as u8);
+        header.extend_from_slice(&epoch.to_le_bytes());
+        header.extend_from_slice(&prefix);
+        let aad = binding.associated_data(&header);
+        match &mut held {
+            Some((_, _, sealed)) => sealed.extend_from_slice(&header),
+            None => writer.write_all(&header).await?,
+        }
+
+        let mut padding = Padding::default();
+        let mut current = Vec::with_capacity(SEGMENT_LEN);
+        let mut next = Vec::with_capacity(SEGMENT_LEN);
+        let mut current_content = fill_padded(reader, &mut current, &mut padding).await?;
+        for counter in 0u32.. {
+            let next_content = fill_padded(reader, &mut next, &mut padding).await?;
+            let last = next.is_empty();
+            let segment = cipher
+                .encrypt(&segment_nonce(&prefix, counter, last), Payload { msg: &current, aad: &aad })
+                .map_err(|e| anyhow!("encryption failure: {}", e))?;
+            match &mut held {
+                Some((hasher, _, sealed)) => {
+                    hasher.update(&current[..current_content]);
+                    sealed.extend_from_slice(&segment);
+                }
+                None => writer.write_all(&segment).await?,
+            }
+            if last {
+                break;
+            }
+            std::mem::swap(&mut current, &mut next);
+            current_content = next_content;
+        }
+        if let Some((hasher, hash, sealed)) = held {
+            if hasher.finalize().as_bytes() != hash {
+                return Err(anyhow!("content hash does not match the streamed plaintext"));
+            }
+            writer.write_all(&sealed).await?;
+        }
+        writer.flush().await?;
+        Ok(padding.read)
+    }
+
+    /// Decrypt a [`Encryptor::seal_stream`] envelope from `reader` into
+    /// `writer` a segment at a time. Anything else sealed by this type is
+    /// read whole and opened with [`Encryptor::open_bound`]. Returns the
+    /// number of plaintext bytes.
+    ///
+    /// Segments are written as they are verified, so on error `writer` may
+    /// hold a prefix of the plaintext that must be discarded.
+    pub async fn open_stream<R, W>(&self, reader: &mut R, writer: &mut W, binding: Binding<'_>) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut header = Vec::with_capacity(STREAM_HEADER_LEN);
+        fill(reader, &mut header, STREAM_HEADER_LEN).await?;
+        if !header.starts_with(MAGIC) || header.get(4) != Some(&STREAM_VERSION) {
+            reader.read_to_end(&mut header).await?;
+            let plaintext = self.open_bound(&header, binding)?;
+            writer.write_all(&plaintext).await?;
+            writer.flush().await?;
+            return Ok(plaintext.len() as u64);
+        }
+        let (cipher, prefix) = self.stream_key(&header)?;
+        let aad = binding.associated_data(&header);
+
+        let mut total = 0;
+        let mut held = None;
+        let mut current = Vec::with_capacity(SEGMENT_LEN + TAG_LEN);
+        let mut next = Vec::with_capacity(SEGMENT_LEN + TAG_LEN);
+        fill(reader, &mut current, SEGMENT_LEN + TAG_LEN).await?;
+        for counter in 0u32.. {
+            fill(reader, &mut next, SEGMENT_LEN + TAG_LEN).await?;
+            let last = next.is_empty();
+            let plaintext = cipher
+                .decrypt(&segment_nonce(&prefix, counter, last), Payload { msg: &current, aad: &aad })
+                .map_err(|e| anyhow!("decryption failure: {}", e))?;
+            total += write_unpadded(writer, &plaintext, &mut held).await?;
+            if last {
+                break;
+            }
+            std::mem::swap(&mut current, &mut next);
+        }
+        if held.is_none() {
+            return Err(anyhow!("invalid padding"));
+        }
+        writer.flush().await?;
+        Ok(total)
+    }
+
+    /// [`Encryptor::open_stream`] for a stream already in memory.
+    fn open_segments(&self, sealed: &[u8], binding: Binding) -> Result<Vec<u8>> {
+        if sealed.len() < STREAM_HEADER_LEN {
+            return Err(anyhow!("sealed stream too short"));
+        }
+        let (header, body) = sealed.split_at(STREAM_HEADER_LEN);
+        let (cipher, prefix) = self.stream_key(header)?;
+        let aad = binding.associated_data(header);
 
+        // Even empty plaintext has one segment, holding only its tag.
+        let segments: Vec<&[u8]> = body.chunks(SEGMENT_LEN + TAG_LEN).collect();
+        if segments.is_empty() {
+            return Err(anyhow!("sealed stream has no segments"));
+        }
+        let mut plaintext = Vec::with_capacity(body.len());
+        for (counter, segment) in segments.iter().enumerate() {
+            let last = counter + 1 == segments.len();
+            let nonce = segment_nonce(&prefix, u32::try_from(counter)?, last);
+            plaintext.extend(
+                cipher
+                    .decrypt(&nonce, Payload { msg: segment, aad: &aad })
+                    .map_err(|e| anyhow!("decryption failure: {}", e))?,
+            );
+        }
+        let len = unpad(&plaintext)?.len();
+        plaintext.truncate(len);
         Ok(plaintext)
     }
+
+    /// Cipher and nonce prefix for a stream, from its header.
+    fn stream_key(&self, header: &[u8]) -> Result<(XChaCha20Poly1305, [u8; PREFIX_LEN])> {
+        if header.len() < STREAM_HEADER_LEN {
+            return Err(anyhow!("sealed stream too short"));
+        }
+        if header[5] != Algorithm::XChaCha20Poly1305 as u8 {
+            return Err(anyhow!("unsupported algorithm {}", header[5]));
+        }
+        let epoch = u32::from_le_bytes(header[6..10].try_into()?);
+        let keys = self.read_keys();
+        let key = keys
+            .keys
+            .get(&epoch)
+            .ok_or_else(|| anyhow!("no key for epoch {}", epoch))?;
+        Ok((key.cipher.clone(), header[10..STREAM_HEADER_LEN].try_into()?))
+    }
+
+    /// [`Encryptor::seal_bound`] for content-addressed data.
+    pub fn seal(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.seal_bound(data, Binding::NONE)
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        self.open_bound(sealed, Binding::NONE)
+    }
+}
+
+fn segment_nonce(prefix: &[u8; PREFIX_LEN], counter: u32, last: bool) -> XNonce {
+    let mut nonce = [0u8; 24];
+    nonce[..PREFIX_LEN].copy_from_slice(prefix);
+    nonce[PREFIX_LEN..23].copy_from_slice(&counter.to_be_bytes());
+    nonce[23] = last as u8;
+    nonce.into()
+}
+
+/// Size of what [`Encryptor::seal_stream`] writes for `len` bytes.
+pub fn sealed_stream_len(len: u64) -> u64 {
+    let padded = padded_len(len + 1);
+    let segments = padded.div_ceil(SEGMENT_LEN as u64);
+    STREAM_HEADER_LEN as u64 + padded + segments * TAG_LEN as u64
+}
+
+/// Read into `buf` until it holds `len` bytes or `reader` is exhausted.
+async fn fill<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut Vec<u8>, len: usize) -> std::io::Result<()> {
+    buf.clear();
+    (&mut *reader).take(len as u64).read_to_end(buf).await?;
+    Ok(())
+}
+
+/// How far a stream being sealed has been read and padded.
+#[derive(Default)]
+struct Padding {
+    /// Plaintext bytes read so far.
+    read: u64,
+    /// Padding bytes still to come, once the plaintext has ended.
+    left: Option<u64>,
+    /// Whether the 0x80 starting the padding has been added.
+    marked: bool,
+}
+
+/// [`fill`] a segment from `reader` followed by its padding: 0x80 and zeros
+/// up to the Padmé bucket of everything `reader` yielded. Returns how many
+/// of the bytes in `buf` are plaintext.
+async fn fill_padded<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut Vec<u8>, padding: &mut Padding) -> std::io::Result<usize> {
+    buf.clear();
+    if padding.left.is_none() {
+        (&mut *reader).take(SEGMENT_LEN as u64).read_to_end(buf).await?;
+        padding.read += buf.len() as u64;
+        if buf.len() < SEGMENT_LEN {
+            padding.left = Some(padded_len(padding.read + 1) - padding.read);
+        }
+    }
+    let content = buf.len();
+    if let Some(left) = &mut padding.left {
+        let added = ((SEGMENT_LEN - content) as u64).min(*left);
+        buf.resize(content + added as usize, 0);
+        if added > 0 && !padding.marked {
+            buf[content] = 0x80;
+            padding.marked = true;
+        }
+        *left -= added;
+    }
+    Ok(content)
+}
+
+/// Write `plaintext` to `writer` but for what may be padding: a 0x80 and
+/// the zeros after it, counted in `held`, are held back until a later
+/// non-zero byte shows they were content. Returns the bytes written.
+async fn write_unpadded<W: AsyncWrite + Unpin>(writer: &mut W, plaintext: &[u8], held: &mut Option<u64>) -> std::io::Result<u64> {
+    let Some(end) = plaintext.iter().rposition(|byte| *byte != 0) else {
+        if let Some(zeros) = held {
+            *zeros += plaintext.len() as u64;
+            return Ok(0);
+        }
+        writer.write_all(plaintext).await?;
+        return Ok(plaintext.len() as u64);
+    };
+    let mut written = 0;
+    if let Some(zeros) = held.take() {
+        writer.write_all(&[0x80]).await?;
+        written += 1 + tokio::io::copy(&mut tokio::io::repeat(0).take(zeros), writer).await?;
+    }
+    let content = if plaintext[end] == 0x80 {
+        *held = Some((plaintext.len() - end - 1) as u64);
+        &plaintext[..end]
+    } else {
+        plaintext
+    };
+    writer.write_all(content).await?;
+    Ok(written + content.len() as u64)
+}
+
+/// Padmé bucket for `len` bytes: at most 12% larger, and a size leaks only
+/// O(log log n) bits instead of O(log n).
+fn padded_len(len: u64) -> u64 {
+    let len = len.max(MIN_PADDED_LEN as u64);
+    let exponent = u64::BITS - 1 - len.leading_zeros();
+    let mantissa_bits = u32::BITS - exponent.leading_zeros();
+    let mask = (1u64 << (exponent - mantissa_bits)) - 1;
+    (len + mask) & !mask
+}
+
+/// Pad `data` up to a size bucket before sealing it for a remote store:
+/// `data` followed by 0x80 and then zeros.
+pub fn pad(data: &[u8]) -> Vec<u8> {
+    let len = padded_len(data.len() as u64 + 1) as usize;
+    let mut padded = Vec::with_capacity(len);
+    padded.extend_from_slice(data);
+    padded.push(0x80);
+    padded.resize(len, 0);
+    padded
+}
+
+/// Strip what [`pad`] added.
+pub fn unpad(padded: &[u8]) -> Result<&[u8]> {
+    match padded.iter().rposition(|byte| *byte != 0) {
+        Some(end) if padded[end] == 0x80 => Ok(&padded[..end]),
+        _ => Err(anyhow!("invalid padding")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pad, sealed_stream_len, unpad, Binding, EncryptionMode, Encryptor, HEADER_LEN, SEGMENT_LEN};
+
+    #[test]
+    fn convergent_mode_seals_equal_content_identically() {
+        let random = Encryptor::new(&[1; 32]);
+        let convergent = Encryptor::with_mode(&[1; 32], EncryptionMode::Convergent);
+        let other_vault = Encryptor::with_mode(&[2; 32], EncryptionMode::Convergent);
+
+        assert_ne!(random.seal(b"note").unwrap(), random.seal(b"note").unwrap());
+        let sealed = convergent.seal(b"note").unwrap();
+        assert_eq!(sealed, convergent.seal(b"note").unwrap());
+        assert_ne!(sealed, convergent.seal(b"other note").unwrap());
+        assert_ne!(sealed, other_vault.seal(b"note").unwrap());
+        // Either mode opens the other's blobs.
+        assert_eq!(random.open(&sealed).unwrap(), b"note");
+    }
+
+    #[test]
+    fn older_epochs_stay_readable_after_rotation() {
+        let encryptor = Encryptor::new(&[1; 32]);
+        let before = encryptor.seal(b"old note").unwrap();
+
+        encryptor.add_epoch(1, &[2; 32]);
+        let after = encryptor.seal(b"new note").unwrap();
+
+        assert_eq!(encryptor.current_epoch(), 1);
+        assert_eq!(encryptor.open(&before).unwrap(), b"old note");
+        assert_eq!(encryptor.open(&after).unwrap(), b"new note");
+        assert!(Encryptor::new(&[1; 32]).open(&after).is_err());
+    }
+
+    #[test]
+    fn bound_blobs_only_open_for_their_path() {
+        let encryptor = Encryptor::with_mode(&[1; 32], EncryptionMode::Convergent);
+        let note = Binding { path: "note.md", file_id: b"note" };
+        let other = Binding { path: "other.md", file_id: b"note" };
+        let sealed = encryptor.seal_bound(b"text", note).unwrap();
+
+        assert!(sealed.starts_with(b"OVSY"));
+        assert_eq!(encryptor.open_bound(&sealed, note).unwrap(), b"text");
+        assert!(encryptor.open_bound(&sealed, other).is_err());
+        assert_ne!(sealed[10..34], encryptor.seal_bound(b"text", other).unwrap()[10..34]);
+
+        // A flipped ciphertext bit fails authentication.
+        let mut tampered = sealed.clone();
+        tampered[HEADER_LEN] ^= 1;
+        assert!(encryptor.open_bound(&tampered, note).is_err());
+    }
+
+    #[test]
+    fn padded_sizes_fall_into_buckets() {
+        assert_eq!(pad(b"short").len(), pad(b"a little longer").len());
+        for len in [0, 1, 511, 512, 4097, 1_000_000] {
+            let data = vec![7u8; len];
+            let padded = pad(&data);
+            assert!(padded.len() > len && padded.len() <= (len + 1).max(512) * 112 / 100);
+            assert_eq!(unpad(&padded).unwrap(), &data[..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_round_trip_and_detect_truncation() {
+        let encryptor = Encryptor::with_mode(&[1; 32], EncryptionMode::Convergent);
+        let note = Binding { path: "video.mp4", file_id: b"video" };
+        // Content that looks like padding across a segment boundary.
+        let mut marked = vec![1u8; SEGMENT_LEN - 1];
+        marked.push(0x80);
+        marked.resize(2 * SEGMENT_LEN + 5, 0);
+        let inputs = [0, 1, SEGMENT_LEN, 3 * SEGMENT_LEN + 7].map(|len| (0..len).map(|i| i as u8).collect::<Vec<u8>>());
+        for data in inputs.into_iter().chain([marked]) {
+            let hash: [u8; 32] = blake3::hash(&data).into();
+            let mut sealed = Vec::new();
+            encryptor.seal_stream(&mut &data[..], &mut sealed, note, Some(&hash)).await.unwrap();
+            assert_eq!(sealed.len() as u64, sealed_stream_len(data.len() as u64));
+
+            let mut opened = Vec::new();
+            encryptor.open_stream(&mut &sealed[..], &mut opened, note).await.unwrap();
+            assert_eq!(opened, data);
+            assert_eq!(encryptor.open_bound(&sealed, note).unwrap(), data);
+
+            let mut again = Vec::new();
+            encryptor.seal_stream(&mut &data[..], &mut again, note, Some(&hash)).await.unwrap();
+            assert_eq!(again, sealed);
+        }
+        // Sizes in one bucket seal to the same length.
+        assert_eq!(sealed_stream_len(3 * SEGMENT_LEN as u64), sealed_stream_len(3 * SEGMENT_LEN as u64 + 7));
+
+        // A hash of other content would reuse its nonces.
+        let stale: [u8; 32] = blake3::hash(b"other content").into();
+        let mut sealed = Vec::new();
+        assert!(encryptor.seal_stream(&mut &b"content"[..], &mut sealed, note, Some(&stale)).await.is_err());
+        assert!(sealed.is_empty());
+
+        let data = vec![3u8; 2 * SEGMENT_LEN + 1];
+        let mut sealed = Vec::new();
+        encryptor.seal_stream(&mut &data[..], &mut sealed, note, None).await.unwrap();
+        let truncated = &sealed[..sealed.len() - 17];
+        assert!(encryptor.open_stream(&mut &truncated[..], &mut Vec::new(), note).await.is_err());
+        assert!(encryptor.open_bound(&sealed, Binding::NONE).is_err());
+
+        let whole = encryptor.seal(b"small").unwrap();
+        let mut opened = Vec::new();
+        encryptor.open_stream(&mut &whole[..], &mut opened, Binding::NONE).await.unwrap();
+        assert_eq!(opened, b"small");
+        assert!(encryptor.open_bound(&whole[4..], Binding::NONE).is_err());
+    }
 }
@@ -1,126 +1,1273 @@
 use anyhow::{anyhow, Result};
-use octocrab::Octocrab;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use crate::engine::encryption::Encryptor;
+use crate::engine::chunker::ChunkReader;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::future::BoxFuture;
+use futures::TryStreamExt;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{Mutex, OnceCell};
+use tokio_util::io::{ReaderStream, StreamReader};
+use crate::engine::encryption::{pad, sealed_stream_len, unpad, Binding, Encryptor};
+use crate::engine::GithubConfig;
+use crate::engine::keys::KeyParams;
+use crate::engine::remote::{RemoteBackend, RemoteEntry, RemoteIndex, MAX_WRITE_ATTEMPTS};
+use crate::engine::version::VersionVector;
 use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+
+mod throttle;
+
+use throttle::Throttle;
 
 pub struct GitHubStorage {
-    client: Octocrab,
+    http: reqwest::Client,
+    token: String,
+    /// Every request waits on this while a rate limit is exhausted.
+    throttle: Throttle,
+    api_url: String,
     owner: String,
     repo: String,
     branch: String,
-    encryptor: Encryptor,
+    large_file_threshold: u64,
+    encryptor: Arc<Encryptor>,
+    /// The release large files are attached to, once found or created.
+    release: OnceCell<Release>,
+    /// The branch as last read.
+    remote: Mutex<Option<RemoteState>>,
+    /// Objects put since the last commit, to go into the next if its index
+    /// refers to them. Locked after `remote` when both are.
+    staged: Mutex<Staged>,
+    /// Set once release assets no index refers to have been collected
+    /// this session.
+    assets_collected: AtomicBool,
+}
+
+#[derive(Default)]
+struct Staged {
+    /// Blob SHA by repository path.
+    blobs: HashMap<String, String>,
+    /// Chunk paths each staged manifest lists.
+    manifests: HashMap<String, Vec<String>>,
+}
+
+impl Staged {
+    /// Unstage the manifest at `object` and the chunks only it lists.
+    fn drop_manifest(&mut self, object: &str) {
+        self.blobs.remove(object);
+        for chunk in self.manifests.remove(object).unwrap_or_default() {
+            if !self.manifests.values().any(|chunks| chunks.contains(&chunk)) {
+                self.blobs.remove(&chunk);
+            }
+        }
+    }
 }
 
+/// What a commit holds under `.oversync`.
+struct RemoteState {
+    commit: String,
+    tree: String,
+    /// Blob SHA of every object, by repository path.
+    objects: HashMap<String, String>,
+    index: RemoteIndex,
+    /// Chunk paths each manifest the index refers to lists.
+    chunks: HashMap<String, Vec<String>>,
+}
+
+/// What the index object holds: the index, and the chunks each manifest it
+/// refers to lists, so chunks no manifest needs any more are found without
+/// reading every manifest.
+#[derive(Default, Serialize, Deserialize)]
+struct StoredIndex {
+    #[serde(flatten)]
+    index: RemoteIndex,
+    /// Missing from indexes stored before chunks were collected.
+    #[serde(default)]
+    chunks: HashMap<String, Vec<String>>,
+}
+
+/// Folder holding content-addressed chunks shared by every file.
+const CHUNK_DIR: &str = ".oversync/chunks";
+
+/// Folder holding each file's manifest, under a random name.
+const FILE_DIR: &str = ".oversync/files";
+
+/// The encrypted index of every file, as one object.
+const INDEX_PATH: &str = ".oversync/index";
+
+/// The vault's key parameters, unencrypted: they are needed to derive the
+/// key, and hold no secret.
+const KEY_PARAMS_PATH: &str = ".oversync/key_params.json";
+
+/// What is stored for a file: the chunks to concatenate.
 #[derive(Debug, Serialize, Deserialize)]
-struct EncryptedBlob {
-    ciphertext: Vec<u8>,
-    nonce: [u8; 24],
+struct ChunkManifest {
+    size: u64,
+    chunks: Vec<String>,
+}
+
+/// File id manifests are bound to; chunks are bound to their name.
+const MANIFEST_ID: &[u8] = b"manifest";
+const INDEX_ID: &[u8] = b"index";
+
+/// Name this backend goes by.
+pub const NAME: &str = "github";
+
+#[derive(Debug, Deserialize)]
+pub struct GitTree {
+    pub sha: String,
+    pub tree: Vec<GitTreeEntry>,
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GitTreeEntry {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub sha: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GitObject {
+    sha: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GitRef {
+    object: GitObject,
+}
+
+#[derive(Debug, Deserialize)]
+struct GitCommit {
+    sha: String,
+    tree: GitObject,
+}
+
+#[derive(Debug, Deserialize)]
+struct GitBlob {
+    content: String,
+    encoding: String,
+}
+
+/// A request GitHub refused.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+    /// Set if it was refused for exceeding a rate limit: when requests may
+    /// be sent again.
+    pub limited_until: Option<DateTime<Utc>>,
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.limited_until {
+            Some(until) => write!(f, "GitHub rate limit exceeded until {}", until.to_rfc3339()),
+            None => write!(f, "GitHub returned {}: {}", self.status, self.message),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// The branch holds files an earlier version stored at their vault paths,
+/// encrypted under the raw passphrase rather than a derived key. Those
+/// aren't migrated.
+#[derive(Debug)]
+pub struct UnmigratedStore;
+
+impl std::fmt::Display for UnmigratedStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the GitHub branch holds files stored by an earlier version of oversync, which can't be read or migrated; \
+             sync to a new, empty branch or repository instead"
+        )
+    }
+}
+
+impl std::error::Error for UnmigratedStore {}
+
+/// Files outside `STATE_DIR` checked for an earlier version's format before
+/// a branch without key parameters is taken to be new.
+const BASELINE_PROBES: usize = 8;
+
+/// Longest wait for a rate limit to pass before retrying a request; longer
+/// ones fail it instead.
+const MAX_RATE_LIMIT_WAIT: chrono::Duration = chrono::Duration::minutes(2);
+
+/// How often a request refused for a rate limit is retried.
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+
+/// Root of everything stored on the remote.
+const STATE_DIR: &str = ".oversync/";
+
+const DEFAULT_API_URL: &str = "https://api.github.com";
+
+/// Files larger than this are stored as release assets unless
+/// `GithubConfig::large_file_threshold` says otherwise.
+const DEFAULT_LARGE_FILE_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+/// Tag of the release holding large files. Assets can be deleted, unlike
+/// blobs in history, so replaced revisions don't accumulate.
+const RELEASE_TAG: &str = "oversync-objects";
+
+/// File id release assets are bound to, along with their name.
+const ASSET_ID: &[u8] = b"asset";
+
+#[derive(Debug, Clone, Deserialize)]
+struct Release {
+    id: u64,
+    /// URL template assets are uploaded to, e.g. `.../assets{?name,label}`.
+    upload_url: String,
+}
+
+/// A large file's content, sealed as one stream and attached to the release.
+#[derive(Debug, Clone, Deserialize)]
+struct ReleaseAsset {
+    id: u64,
+    name: String,
+}
+
+/// A release asset as listed, with when it was uploaded.
+#[derive(Debug, Deserialize)]
+struct ListedAsset {
+    #[serde(flatten)]
+    asset: ReleaseAsset,
+    created_at: DateTime<Utc>,
+}
+
+/// Assets listed per request.
+const ASSET_PAGE_LEN: usize = 100;
+
+/// How old an asset no index refers to has to be before it is deleted.
+/// Younger ones may be uploads of a push still under way on another device.
+const ORPHAN_ASSET_AGE: chrono::Duration = chrono::Duration::hours(1);
+
+/// Prefix of the objects of entries stored as release assets, followed by
+/// the asset's id and name. Manifests are under `FILE_DIR` instead.
+const ASSET_OBJECT: &str = "asset:";
+
+impl ReleaseAsset {
+    /// The asset `object` refers to, if it refers to one.
+    fn parse(object: &str) -> Option<Self> {
+        let (id, name) = object.strip_prefix(ASSET_OBJECT)?.split_once('/')?;
+        Some(Self { id: id.parse().ok()?, name: name.to_string() })
+    }
+
+    fn object(&self) -> String {
+        format!("{}{}/{}", ASSET_OBJECT, self.id, self.name)
+    }
 }
 
 impl GitHubStorage {
-    pub fn new(token: String, owner: String, repo: String, branch: String, encryption_key: &[u8; 32]) -> Result<Self> {
-        let client = Octocrab::builder()
-            .personal_token(token)
+    pub fn new(config: GithubConfig, encryptor: Arc<Encryptor>) -> Result<Self> {
+        let api_url = config.api_url.unwrap_or_else(|| DEFAULT_API_URL.to_string());
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("application/vnd.github+json"));
+        headers.insert("x-github-api-version", HeaderValue::from_static("2022-11-28"));
+        let http = reqwest::Client::builder()
+            .user_agent("oversync")
+            .default_headers(headers)
             .build()?;
-        
+
         Ok(Self {
-            client,
-            owner,
-            repo,
-            branch,
-            encryptor: Encryptor::new(encryption_key),
+            http,
+            token: config.token,
+            throttle: Throttle::default(),
+            api_url: api_url.trim_end_matches('/').to_string(),
+            owner: config.owner,
+            repo: config.repo,
+            branch: config.branch,
+            large_file_threshold: config.large_file_threshold.unwrap_or(DEFAULT_LARGE_FILE_THRESHOLD),
+            encryptor,
+            release: OnceCell::new(),
+            assets_collected: AtomicBool::new(false),
+            remote: Mutex::new(None),
+            staged: Mutex::new(Staged::default()),
         })
     }
 
-    pub async fn upload_file(&self, path: &str, content: &[u8]) -> Result<String> {
-        let (ciphertext, nonce) = self.encryptor.encrypt(content)?;
-        let blob_data = EncryptedBlob { ciphertext, nonce };
-        let serialized = serde_json::to_vec(&blob_data)?;
-        let encoded = general_purpose::STANDARD.encode(&serialized);
+    /// URL of `route` under the repository.
+    fn url(&self, route: &str) -> String {
+        format!("{}/repos/{}/{}/{}", self.api_url, self.owner, self.repo, route)
+    }
 
-        let content_response = self.client
-            .repos(&self.owner, &self.repo)
-            .create_file(path, "Upload file", encoded)
-            .branch(&self.branch)
-            .send()
-            .await?;
+    /// Send the request `build` makes, authenticated, once rate limits
+    /// allow it. One refused for a rate limit that passes within
+    /// `MAX_RATE_LIMIT_WAIT` is sent again after it. Fails with an
+    /// [`ApiError`] unless GitHub accepts it.
+    async fn send(&self, build: impl Fn() -> RequestBuilder) -> Result<Response> {
+        let mut retries = 0;
+        loop {
+            let request = build().bearer_auth(&self.token).build()?;
+            let write = request.method() != Method::GET;
+            self.throttle.ready(write).await;
+            let response = self.http.execute(request).await?;
+            match self.check(write, response).await {
+                Err(ApiError { limited_until: Some(until), .. })
+                    if retries < MAX_RATE_LIMIT_RETRIES && until <= Utc::now() + MAX_RATE_LIMIT_WAIT =>
+                {
+                    retries += 1
+                }
+                checked => return Ok(checked?),
+            }
+        }
+    }
 
-        Ok(content_response.content.sha)
+    /// `response`, to a request that wrote if `write`, if it succeeded, else
+    /// why not. Either way, the throttle learns the rate limits it reports.
+    async fn check(&self, write: bool, response: Response) -> std::result::Result<Response, ApiError> {
+        let status = response.status();
+        if status.is_success() {
+            self.throttle.observe(write, status, response.headers(), "");
+            return Ok(response);
+        }
+        let headers = response.headers().clone();
+        let body = response.text().await.unwrap_or_default();
+        let message = serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|error| Some(error.get("message")?.as_str()?.to_string()))
+            .unwrap_or(body);
+        let limited_until = self.throttle.observe(write, status, &headers, &message);
+        Err(ApiError { status, message, limited_until })
     }
 
-    pub async fn download_file(&self, sha: &str) -> Result<Vec<u8>> {
-        let blob = self.client
-            .repos(&self.owner, &self.repo)
-            .get_content()
-            .path(sha)
-            .send()
-            .await?;
+    async fn get<T: DeserializeOwned>(&self, route: &str) -> Result<T> {
+        Ok(self.send(|| self.http.get(self.url(route))).await?.json().await?)
+    }
 
-        let item = blob.items.first().ok_or_else(|| anyhow!("No items found"))?;
-        let decoded = general_purpose::STANDARD.decode(item.content.as_ref().ok_or_else(|| anyhow!("No content in item"))?.trim().replace("\n", ""))?;
-        let encrypted: EncryptedBlob = serde_json::from_slice(&decoded)?;
-        
-        let plaintext = self.encryptor.decrypt(&encrypted.ciphertext, &encrypted.nonce)?;
-        Ok(plaintext)
+    async fn post<T: DeserializeOwned>(&self, route: &str, body: &serde_json::Value) -> Result<T> {
+        Ok(self.send(|| self.http.post(self.url(route)).json(body)).await?.json().await?)
     }
 
-    pub async fn get_tree(&self, sha: &str) -> Result<octocrab::models::commits::Tree> {
-        let tree = self.client
-            .get(format!("/repos/{}/{}/git/trees/{}", self.owner, self.repo, sha), None::<&()>)
+    fn seal(&self, path: &str, content: &[u8], file_id: &[u8]) -> Result<Vec<u8>> {
+        self.encryptor.seal_bound(&pad(content), Binding { path, file_id })
+    }
+
+    /// Seal what `reader` yields, `size` bytes, as one stream padded to a
+    /// size bucket and attach it to the release under a random name. Returns the file's entry,
+    /// without a version.
+    async fn stage_asset<R: AsyncRead + Unpin>(&self, reader: R, size: u64) -> Result<RemoteEntry> {
+        let release = self.release().await?;
+        let name = format!("{}.bin", uuid::Uuid::new_v4().simple());
+        let url = format!(
+            "{}?name={}",
+            release.upload_url.split('{').next().unwrap_or_default(),
+            name
+        );
+
+        let mut reader = HashingReader::new(reader);
+        let (mut writer, body) = tokio::io::duplex(SEGMENT_BUFFER_LEN);
+        let seal = async {
+            let sealed = self
+                .encryptor
+                .seal_stream(&mut reader, &mut writer, Binding { path: &name, file_id: ASSET_ID }, None)
+                .await;
+            drop(writer);
+            sealed
+        };
+        let upload = async {
+            // The body streams, so can't be sent again: a rate limit fails
+            // the push, leaving it to be retried.
+            self.throttle.ready(true).await;
+            let response = self
+                .http
+                .post(&url)
+                .bearer_auth(&self.token)
+                .header(CONTENT_TYPE, "application/octet-stream")
+                .header(CONTENT_LENGTH, sealed_stream_len(size))
+                .body(reqwest::Body::wrap_stream(ReaderStream::new(body)))
+                .send()
+                .await?;
+            anyhow::Ok(self.check(true, response).await?.json::<ReleaseAsset>().await?)
+        };
+        let (sealed, uploaded) = tokio::join!(seal, upload);
+        // A failed read ends the body early, so the upload fails too.
+        sealed?;
+        let asset = uploaded?;
+        let (hash, read) = reader.finish();
+        if read != size {
+            let _ = self.delete_asset(asset.id).await;
+            return Err(anyhow!("file changed size while being uploaded"));
+        }
+        Ok(RemoteEntry {
+            object: Some(asset.object()),
+            size,
+            hash,
+            version: VersionVector::default(),
+            modified: 0,
+            tombstone: None,
+        })
+    }
+
+    /// The release large files are attached to, created the first time.
+    async fn release(&self) -> Result<&Release> {
+        self.release
+            .get_or_try_init(|| async {
+                if let Some(release) = self.find_release().await? {
+                    return Ok(release);
+                }
+                let created = self
+                    .post(
+                        "releases",
+                        &serde_json::json!({
+                            "tag_name": RELEASE_TAG,
+                            "target_commitish": self.branch,
+                            "name": "OverSync objects",
+                            "body": "Encrypted large files synced by OverSync.",
+                            "prerelease": true,
+                        }),
+                    )
+                    .await;
+                match created {
+                    Ok(release) => Ok(release),
+                    // Refused as a duplicate if another device created it
+                    // first; for anything else it is still missing.
+                    Err(e) if status_of(&e) == Some(StatusCode::UNPROCESSABLE_ENTITY) => self.find_release().await?.ok_or(e),
+                    Err(e) => Err(e),
+                }
+            })
+            .await
+    }
+
+    /// The release large files are attached to, if it exists.
+    async fn find_release(&self) -> Result<Option<Release>> {
+        if let Some(release) = self.release.get() {
+            return Ok(Some(release.clone()));
+        }
+        match self.get(&format!("releases/tags/{}", RELEASE_TAG)).await {
+            Ok(release) => Ok(Some(release)),
+            Err(e) if status_of(&e) == Some(StatusCode::NOT_FOUND) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Delete release assets no entry in `index` refers to and that are
+    /// older than `ORPHAN_ASSET_AGE`: uploads of pushes that never landed,
+    /// and replaced assets whose deletion failed.
+    async fn collect_assets(&self, index: &RemoteIndex) -> Result<()> {
+        let Some(release) = self.find_release().await? else {
+            return Ok(());
+        };
+        let live: HashSet<u64> = index
+            .files
+            .values()
+            .filter_map(|entry| ReleaseAsset::parse(entry.object.as_deref()?))
+            .map(|asset| asset.id)
+            .collect();
+        let cutoff = Utc::now() - ORPHAN_ASSET_AGE;
+        // Listed in full first, as deleting shifts the pages.
+        let mut orphans = Vec::new();
+        for page in 1.. {
+            let route = format!("releases/{}/assets?per_page={}&page={}", release.id, ASSET_PAGE_LEN, page);
+            let listed: Vec<ListedAsset> = self.get(&route).await?;
+            let last = listed.len() < ASSET_PAGE_LEN;
+            orphans.extend(
+                listed
+                    .into_iter()
+                    .filter(|listed| !live.contains(&listed.asset.id) && listed.created_at < cutoff)
+                    .map(|listed| listed.asset),
+            );
+            if last {
+                break;
+            }
+        }
+        for asset in orphans {
+            self.delete_asset(asset.id).await?;
+        }
+        Ok(())
+    }
+
+    /// Decrypt release asset `asset` into `writer` as it downloads.
+    async fn download_asset<W: AsyncWrite + Unpin>(&self, asset: &ReleaseAsset, writer: &mut W) -> Result<u64> {
+        let url = self.url(&format!("releases/assets/{}", asset.id));
+        // Redirects to storage elsewhere, which reqwest follows without
+        // passing on the token.
+        let response = self
+            .send(|| self.http.get(&url).header(ACCEPT, "application/octet-stream"))
             .await?;
-        Ok(tree)
+        let mut body = StreamReader::new(response.bytes_stream().map_err(std::io::Error::other));
+        let binding = Binding { path: &asset.name, file_id: ASSET_ID };
+        self.encryptor.open_stream(&mut body, writer, binding).await
+    }
+
+    async fn delete_asset(&self, id: u64) -> Result<()> {
+        let url = self.url(&format!("releases/assets/{}", id));
+        match self.send(|| self.http.delete(&url)).await {
+            Err(e) if status_of(&e) != Some(StatusCode::NOT_FOUND) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Create blobs for the chunks of what `reader` yields that aren't in
+    /// `objects` or `staged`, and for its manifest under a random name,
+    /// staging them. Returns the file's entry, without a version.
+    async fn stage_file<R: AsyncRead + Unpin>(
+        &self,
+        reader: R,
+        objects: &HashMap<String, String>,
+        staged: &mut Staged,
+    ) -> Result<RemoteEntry> {
+        // Names are keyed per epoch, so a rotation re-uploads every chunk.
+        let name_key = self.encryptor.name_key();
+        let mut names = Vec::new();
+        let mut paths = Vec::new();
+        let mut hasher = blake3::Hasher::new();
+        let mut size = 0;
+        let mut chunks = ChunkReader::new(reader);
+        while let Some(piece) = chunks.next_chunk().await? {
+            let name = blake3::keyed_hash(&name_key, &piece).to_hex().to_string();
+            let chunk_path = format!("{}/{}", CHUNK_DIR, name);
+            if !objects.contains_key(&chunk_path) && !staged.blobs.contains_key(&chunk_path) {
+                let sealed = self.seal(&chunk_path, &piece, name.as_bytes())?;
+                staged.blobs.insert(chunk_path.clone(), self.create_blob(&sealed).await?);
+            }
+            paths.push(chunk_path);
+            hasher.update(&piece);
+            size += piece.len() as u64;
+            names.push(name);
+        }
+
+        let manifest = ChunkManifest { size, chunks: names };
+        let object = format!("{}/{}", FILE_DIR, uuid::Uuid::new_v4().simple());
+        let sealed = self.seal(&object, &serde_json::to_vec(&manifest)?, MANIFEST_ID)?;
+        staged.blobs.insert(object.clone(), self.create_blob(&sealed).await?);
+        staged.manifests.insert(object.clone(), paths);
+        Ok(RemoteEntry {
+            object: Some(object),
+            size,
+            hash: hasher.finalize().into(),
+            version: VersionVector::default(),
+            modified: 0,
+            tombstone: None,
+        })
+    }
+
+    /// Reassemble the file whose manifest is at `object` into `writer`, a
+    /// chunk at a time. Returns its size.
+    ///
+    /// The file is read as of the branch's last listing.
+    async fn download_chunked<W: AsyncWrite + Unpin>(&self, object: &str, writer: &mut W) -> Result<u64> {
+        let (manifest, shas) = {
+            let mut cached = self.remote.lock().await;
+            let state = self.load(&mut cached, false).await?;
+            let manifest = self.read_manifest(&state.objects, object).await?;
+            let shas = manifest
+                .chunks
+                .iter()
+                .map(|name| object_sha(&state.objects, &format!("{}/{}", CHUNK_DIR, name)).map(str::to_string))
+                .collect::<Result<Vec<_>>>()?;
+            (manifest, shas)
+        };
+
+        let mut size = 0;
+        for (name, sha) in manifest.chunks.iter().zip(&shas) {
+            let chunk_path = format!("{}/{}", CHUNK_DIR, name);
+            let piece = self.download_blob(sha, &chunk_path, name.as_bytes()).await?;
+            writer.write_all(&piece).await?;
+            size += piece.len() as u64;
+        }
+        writer.flush().await?;
+        if size != manifest.size {
+            return Err(anyhow!("size mismatch reassembling {}", object));
+        }
+        Ok(size)
     }
 
-    pub async fn update_state(&self, path: &str, sha: &str, message: &str) -> Result<()> {
-        let repo = self.client.repos(&self.owner, &self.repo);
-        
-        let branch_ref = repo.get_ref(&octocrab::params::repos::Reference::Branch(self.branch.clone())).await?;
-        let parent_sha = match branch_ref.object {
-            octocrab::models::repos::Object::Commit { sha, .. } => sha,
-            octocrab::models::repos::Object::Tag { sha, .. } => sha,
-            _ => return Err(anyhow!("Unexpected ref object type")),
+    /// The manifest at `object` among `objects`.
+    async fn read_manifest(&self, objects: &HashMap<String, String>, object: &str) -> Result<ChunkManifest> {
+        let sha = object_sha(objects, object)?;
+        Ok(serde_json::from_slice(&self.download_blob(sha, object, MANIFEST_ID).await?)?)
+    }
+
+    /// The branch's state, read again if `refresh` is set and the branch
+    /// has moved, or if it was never read.
+    async fn load<'a>(&self, cached: &'a mut Option<RemoteState>, refresh: bool) -> Result<&'a mut RemoteState> {
+        if refresh || cached.is_none() {
+            let head = self.head().await?;
+            if cached.as_ref().is_none_or(|state| state.commit != head.sha) {
+                *cached = Some(self.read_state(head).await?);
+            }
+        }
+        Ok(cached.as_mut().expect("state was just loaded"))
+    }
+
+    async fn read_state(&self, head: GitCommit) -> Result<RemoteState> {
+        let tree = self.get_tree(&head.tree.sha, true).await?;
+        if tree.truncated {
+            return Err(anyhow!("the repository has too many objects to list"));
+        }
+        let objects: HashMap<String, String> = tree
+            .tree
+            .into_iter()
+            .filter(|entry| entry.kind == "blob" && entry.path.starts_with(STATE_DIR))
+            .map(|entry| (entry.path, entry.sha))
+            .collect();
+        let stored: StoredIndex = match objects.get(INDEX_PATH) {
+            Some(sha) => serde_json::from_slice(&self.download_blob(sha, INDEX_PATH, INDEX_ID).await?)?,
+            None => StoredIndex::default(),
         };
+        Ok(RemoteState {
+            commit: head.sha,
+            tree: head.tree.sha,
+            objects,
+            index: stored.index,
+            chunks: stored.chunks,
+        })
+    }
+
+    async fn create_blob(&self, content: &[u8]) -> Result<String> {
+        let blob: GitObject = self
+            .post(
+                "git/blobs",
+                &serde_json::json!({
+                    "content": general_purpose::STANDARD.encode(content),
+                    "encoding": "base64",
+                }),
+            )
+            .await?;
+        Ok(blob.sha)
+    }
+
+    /// Fetch blob `sha` and decrypt it as what was sealed for `path` and
+    /// `file_id`. Only objects under `STATE_DIR` are read; files stored at
+    /// their vault paths by earlier versions aren't migrated.
+    async fn download_blob(&self, sha: &str, path: &str, file_id: &[u8]) -> Result<Vec<u8>> {
+        let padded = self.encryptor.open_bound(&self.download_raw(sha).await?, Binding { path, file_id })?;
+        Ok(unpad(&padded)?.to_vec())
+    }
+
+    /// Blob `sha` as stored.
+    async fn download_raw(&self, sha: &str) -> Result<Vec<u8>> {
+        let blob: GitBlob = self.get(&format!("git/blobs/{}", sha)).await?;
+        if blob.encoding != "base64" {
+            return Err(anyhow!("unexpected {} encoding of blob {}", blob.encoding, sha));
+        }
+        Ok(general_purpose::STANDARD.decode(blob.content.replace('\n', ""))?)
+    }
+
+    /// The key parameters published on the branch `config` names, read before
+    /// the vault key is known. Nothing read is encrypted, so the key the
+    /// storage is opened with here doesn't matter.
+    ///
+    /// A branch without any that holds an earlier version's files fails
+    /// with [`UnmigratedStore`].
+    pub async fn published_key_params(config: GithubConfig) -> Result<Option<KeyParams>> {
+        let storage = Self::new(config, Arc::new(Encryptor::new(&[0; 32])))?;
+        let params = storage.key_params().await?;
+        if params.is_none() && storage.holds_unmigrated_files().await? {
+            return Err(UnmigratedStore.into());
+        }
+        Ok(params)
+    }
+
+    /// Whether the branch holds files an earlier version stored: base64 of
+    /// a JSON object with the ciphertext and nonce, at the file's path.
+    async fn holds_unmigrated_files(&self) -> Result<bool> {
+        let head = self.head().await?;
+        let tree = self.get_tree(&head.tree.sha, true).await?;
+        let files = tree
+            .tree
+            .into_iter()
+            .filter(|entry| entry.kind == "blob" && !entry.path.starts_with(STATE_DIR));
+        for entry in files.take(BASELINE_PROBES) {
+            let stored = self.download_raw(&entry.sha).await?;
+            let object = general_purpose::STANDARD
+                .decode(&stored)
+                .ok()
+                .and_then(|json| serde_json::from_slice::<serde_json::Value>(&json).ok());
+            if object.is_some_and(|object| object.get("ciphertext").is_some() && object.get("nonce").is_some()) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Tree `sha`, with every subtree's entries too if `recursive` is set.
+    pub async fn get_tree(&self, sha: &str, recursive: bool) -> Result<GitTree> {
+        let url = self.url(&format!("git/trees/{}", sha));
+        let query: &[(&str, &str)] = if recursive { &[("recursive", "1")] } else { &[] };
+        Ok(self.send(|| self.http.get(&url).query(query)).await?.json().await?)
+    }
+
+    /// The commit the branch points at.
+    async fn head(&self) -> Result<GitCommit> {
+        let branch_ref: GitRef = self.get(&format!("git/ref/heads/{}", self.branch)).await?;
+        self.get(&format!("git/commits/{}", branch_ref.object.sha)).await
+    }
+
+    /// Commit `changes` (repository path to blob SHA, or `None` to delete
+    /// the path) on top of `base` and move the branch to it. The ref update
+    /// isn't forced: if the branch no longer points at `base`, it is left
+    /// alone and `false` returned.
+    async fn update_state(&self, base: &RemoteState, changes: &BTreeMap<String, Option<String>>, message: &str) -> Result<bool> {
+        let entries: Vec<serde_json::Value> = changes
+            .iter()
+            .map(|(path, sha)| {
+                serde_json::json!({
+                    "path": path,
+                    "mode": "100644",
+                    "type": "blob",
+                    "sha": sha,
+                })
+            })
+            .collect();
 
         // Create a new tree
-        let tree: octocrab::models::commits::Tree = self.client
+        let tree: GitObject = self
             .post(
-                format!("/repos/{}/{}/git/trees", self.owner, self.repo),
-                Some(&serde_json::json!({
-                    "base_tree": parent_sha,
-                    "tree": [{
-                        "path": path,
-                        "mode": "100644",
-                        "type": "blob",
-                        "sha": sha,
-                    }]
-                })),
+                "git/trees",
+                &serde_json::json!({
+                    "base_tree": base.tree,
+                    "tree": entries,
+                }),
             )
             .await?;
 
         // Create a commit
-        let commit: octocrab::models::commits::GitCommitObject = self.client
+        let commit: GitObject = self
             .post(
-                format!("/repos/{}/{}/git/commits", self.owner, self.repo),
-                Some(&serde_json::json!({
+                "git/commits",
+                &serde_json::json!({
                     "message": message,
                     "tree": tree.sha,
-                    "parents": [parent_sha]
-                })),
+                    "parents": [base.commit]
+                }),
             )
             .await?;
 
         // Update the ref
-        let _ : serde_json::Value = self.client
-            .patch(
-                format!("/repos/{}/{}/git/refs/heads/{}", self.owner, self.repo, self.branch),
-                Some(&serde_json::json!({
-                    "sha": commit.sha,
-                    "force": false
-                })),
-            )
-            .await?;
+        let url = self.url(&format!("git/refs/heads/{}", self.branch));
+        let body = serde_json::json!({
+            "sha": commit.sha,
+            "force": false
+        });
+        match self.send(|| self.http.patch(&url).json(&body)).await {
+            Ok(_) => Ok(true),
+            // Not a fast-forward: someone else committed meanwhile.
+            Err(e) if is_conflict(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
 
-        Ok(())
+/// Content is stored as content-defined chunks, skipping chunks the
+/// repository already has, and a manifest listing them; files above the
+/// large file threshold are attached to a release instead. The manifest is
+/// the encrypted index, replaced in a commit along with every object put or
+/// deleted since the last one and the chunks no manifest lists any more.
+impl RemoteBackend for GitHubStorage {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn limited_until(&self) -> Option<DateTime<Utc>> {
+        self.throttle.limited_until()
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<(String, RemoteIndex)>> {
+        Box::pin(async move {
+            let mut cached = self.remote.lock().await;
+            let state = self.load(&mut cached, true).await?;
+            Ok((state.commit.clone(), state.index.clone()))
+        })
+    }
+
+    fn get<'a>(&'a self, entry: &'a RemoteEntry, writer: &'a mut (dyn AsyncWrite + Send + Unpin)) -> BoxFuture<'a, Result<u64>> {
+        Box::pin(async move {
+            let object = entry.object.as_deref().ok_or_else(|| anyhow!("the entry has no content"))?;
+            match ReleaseAsset::parse(object) {
+                Some(asset) => self.download_asset(&asset, &mut &mut *writer).await,
+                None => self.download_chunked(object, &mut &mut *writer).await,
+            }
+        })
+    }
+
+    fn put<'a>(&'a self, reader: &'a mut (dyn AsyncRead + Send + Unpin), size: u64) -> BoxFuture<'a, Result<RemoteEntry>> {
+        Box::pin(async move {
+            if size > self.large_file_threshold {
+                return self.stage_asset(reader, size).await;
+            }
+            // Assume the branch is where it was last read; chunks another
+            // device added since are only uploaded twice.
+            let mut cached = self.remote.lock().await;
+            let state = self.load(&mut cached, false).await?;
+            let mut staged = self.staged.lock().await;
+            self.stage_file(reader, &state.objects, &mut staged).await
+        })
+    }
+
+    fn delete<'a>(&'a self, entry: &'a RemoteEntry) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let Some(object) = &entry.object else {
+                return Ok(());
+            };
+            match ReleaseAsset::parse(object) {
+                Some(asset) => self.delete_asset(asset.id).await,
+                None => {
+                    self.staged.lock().await.drop_manifest(object);
+                    Ok(())
+                }
+            }
+        })
+    }
+
+    fn swap_manifest<'a>(&'a self, version: &'a str, index: &'a RemoteIndex, message: &'a str) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(async move {
+            let mut cached = self.remote.lock().await;
+            let state = self.load(&mut cached, false).await?;
+            if state.commit != version {
+                return Ok(false);
+            }
+            let live: HashSet<&str> = index.files.values().filter_map(|entry| entry.object.as_deref()).collect();
+            let mut changes = BTreeMap::new();
+            let mut committed = Vec::new();
+            let mut chunks = HashMap::new();
+            {
+                // Staged manifests the index refers to go in with their
+                // chunks; the rest were left out and are unstaged by `delete`.
+                let staged = self.staged.lock().await;
+                for (object, listed) in staged.manifests.iter().filter(|(object, _)| live.contains(object.as_str())) {
+                    for path in std::iter::once(object).chain(listed) {
+                        if let Some(sha) = staged.blobs.get(path) {
+                            changes.insert(path.clone(), Some(sha.clone()));
+                        }
+                    }
+                    committed.push(object.clone());
+                }
+                for object in live.iter().filter(|object| is_under(object, FILE_DIR)) {
+                    let listed = match staged.manifests.get(*object).or(state.chunks.get(*object)) {
+                        Some(listed) => listed.clone(),
+                        // Gone already; its entry can't be read either way.
+                        None if !state.objects.contains_key(*object) => Vec::new(),
+                        // Stored before chunk lists were kept.
+                        None => {
+                            let manifest = self.read_manifest(&state.objects, object).await?;
+                            manifest.chunks.iter().map(|name| format!("{}/{}", CHUNK_DIR, name)).collect()
+                        }
+                    };
+                    chunks.insert(object.to_string(), listed);
+                }
+            }
+            let needed: HashSet<&String> = chunks.values().flatten().collect();
+            // Put skips chunks the branch had as last read; another device
+            // may have collected one since. The push fails and is retried
+            // against the branch as it is now, uploading the chunk again.
+            if let Some(missing) = needed.iter().find(|path| !state.objects.contains_key(**path) && !changes.contains_key(**path)) {
+                return Err(anyhow!("{} was collected from the remote meanwhile", missing));
+            }
+
+            // Manifests the index no longer refers to and chunks no manifest
+            // lists go out in the same commit; assets once it has landed.
+            for path in state.objects.keys() {
+                let unused = (is_under(path, FILE_DIR) && !live.contains(path.as_str()))
+                    || (is_under(path, CHUNK_DIR) && !needed.contains(path));
+                if unused {
+                    changes.insert(path.clone(), None);
+                }
+            }
+            let replaced_assets: Vec<ReleaseAsset> = state
+                .index
+                .files
+                .values()
+                .filter_map(|entry| entry.object.as_deref())
+                .filter(|object| !live.contains(object))
+                .filter_map(ReleaseAsset::parse)
+                .collect();
+            let stored = StoredIndex { index: index.clone(), chunks };
+            let sealed = self.seal(INDEX_PATH, &serde_json::to_vec(&stored)?, INDEX_ID)?;
+            changes.insert(INDEX_PATH.to_string(), Some(self.create_blob(&sealed).await?));
+
+            let updated = self.update_state(state, &changes, message).await;
+            // Read the branch again next time, whether or not this landed.
+            *cached = None;
+            if !updated? {
+                return Ok(false);
+            }
+            let mut staged = self.staged.lock().await;
+            for object in committed {
+                staged.blobs.remove(&object);
+                for chunk in staged.manifests.remove(&object).unwrap_or_default() {
+                    staged.blobs.remove(&chunk);
+                }
+            }
+            drop(staged);
+            for asset in replaced_assets {
+                if let Err(e) = self.delete_asset(asset.id).await {
+                    eprintln!("Failed to delete replaced asset {}: {}", asset.name, e);
+                }
+            }
+            if !self.assets_collected.swap(true, Ordering::Relaxed) {
+                if let Err(e) = self.collect_assets(index).await {
+                    eprintln!("Failed to collect orphaned release assets: {}", e);
+                    self.assets_collected.store(false, Ordering::Relaxed);
+                }
+            }
+            Ok(true)
+        })
+    }
+
+    fn key_params(&self) -> BoxFuture<'_, Result<Option<KeyParams>>> {
+        Box::pin(async move {
+            // Found without reading the index, which takes the key to open.
+            let head = self.head().await?;
+            let tree = self.get_tree(&head.tree.sha, true).await?;
+            match tree.tree.into_iter().find(|entry| entry.path == KEY_PARAMS_PATH).map(|entry| entry.sha) {
+                Some(sha) => Ok(Some(serde_json::from_slice(&self.download_raw(&sha).await?)?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn publish_key_params<'a>(&'a self, params: &'a KeyParams) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let sha = self.create_blob(&serde_json::to_vec_pretty(params)?).await?;
+            let changes = BTreeMap::from([(KEY_PARAMS_PATH.to_string(), Some(sha))]);
+            let mut cached = self.remote.lock().await;
+            for _ in 0..MAX_WRITE_ATTEMPTS {
+                let state = self.load(&mut cached, true).await?;
+                let updated = self.update_state(state, &changes, "Publish key parameters").await;
+                *cached = None;
+                if updated? {
+                    return Ok(());
+                }
+            }
+            Err(anyhow!("the branch kept moving; gave up publishing key parameters after {} attempts", MAX_WRITE_ATTEMPTS))
+        })
+    }
+}
+
+/// Whether repository path `path` is in folder `dir`.
+fn is_under(path: &str, dir: &str) -> bool {
+    path.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Blob SHA of the object at repository path `path`.
+fn object_sha<'a>(objects: &'a HashMap<String, String>, path: &str) -> Result<&'a str> {
+    objects.get(path).map(String::as_str).ok_or_else(|| anyhow!("{} is missing from the remote", path))
+}
+
+/// Whether a write failed because the branch or path changed under it:
+/// 409 Conflict for a stale SHA, or 422 for a ref update that isn't a
+/// fast forward. Any other 422 is a validation error, which a retry can't
+/// fix.
+fn is_conflict(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<ApiError>() {
+        Some(error) if error.status == StatusCode::CONFLICT => true,
+        Some(error) if error.status == StatusCode::UNPROCESSABLE_ENTITY => {
+            error.message.to_lowercase().contains("not a fast forward")
+        }
+        _ => false,
+    }
+}
+
+/// Status GitHub refused a request with, if that is what `error` is.
+fn status_of(error: &anyhow::Error) -> Option<StatusCode> {
+    error.downcast_ref::<ApiError>().map(|error| error.status)
+}
+
+/// Bytes buffered between sealing a large file and uploading it.
+const SEGMENT_BUFFER_LEN: usize = 256 * 1024;
+
+/// Passes reads through, hashing and counting what they return.
+struct HashingReader<R> {
+    inner: R,
+    hasher: blake3::Hasher,
+    read: u64,
+}
+
+impl<R> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: blake3::Hasher::new(),
+            read: 0,
+        }
+    }
+
+    /// Hash and length of everything read.
+    fn finish(self) -> ([u8; 32], u64) {
+        (self.hasher.finalize().into(), self.read)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = polled {
+            let new = &buf.filled()[before..];
+            self.hasher.update(new);
+            self.read += new.len() as u64;
+        }
+        polled
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock;
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{MockGitHub, BRANCH, OWNER, REPO};
+    use super::{is_conflict, ApiError, GitHubStorage, UnmigratedStore};
+    use crate::engine::encryption::{sealed_stream_len, Encryptor};
+    use crate::engine::keys::{KdfParams, KeyParams};
+    use crate::engine::remote::{Remote, RemoteBackend};
+    use crate::engine::storage::{FileMetadata, VaultIndexer};
+    use crate::engine::GithubConfig;
+    use base64::{engine::general_purpose, Engine as _};
+    use rand::RngCore;
+    use reqwest::StatusCode;
+    use std::sync::Arc;
+
+    fn config(api_url: String) -> GithubConfig {
+        GithubConfig {
+            token: "token".into(),
+            owner: OWNER.into(),
+            repo: REPO.into(),
+            branch: BRANCH.into(),
+            api_url: Some(api_url),
+            large_file_threshold: Some(1024 * 1024),
+        }
+    }
+
+    fn device(api_url: String) -> Arc<GitHubStorage> {
+        Arc::new(GitHubStorage::new(config(api_url), Arc::new(Encryptor::new(&[9u8; 32]))).unwrap())
+    }
+
+    fn edit(indexer: &mut VaultIndexer, path: &str, content: &[u8]) -> FileMetadata {
+        indexer.update_file(path.to_string(), content, 0).unwrap();
+        indexer.get_metadata(path).unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn pushes_from_one_device_are_pulled_by_another() {
+        let (github, server) = MockGitHub::start().await;
+        let (a, b) = (Remote::new(device(server.uri())), device(server.uri()));
+        let mut index = VaultIndexer::new();
+        let mut large = vec![0u8; 3 * 1024 * 1024];
+        rand::thread_rng().fill_bytes(&mut large);
+
+        let (start, listed) = b.list().await.unwrap();
+        assert!(listed.files.is_empty());
+        let uploads = vec![
+            (edit(&mut index, "notes/hello.md", b"hello"), &b"hello"[..]),
+            (edit(&mut index, "big.pdf", &large), &large[..]),
+        ];
+        a.push(uploads, Vec::new()).await.unwrap();
+        assert!(github.paths().iter().all(|path| !path.contains("hello") && !path.contains("big")));
+        // Over the threshold, so attached to the release rather than chunked.
+        assert_eq!(github.assets().len(), 1);
+
+        let (commit, listed) = b.list().await.unwrap();
+        assert_ne!(commit, start);
+        assert_eq!(listed.files.len(), 2);
+        let big = &listed.files["big.pdf"];
+        assert_eq!(big.hash, *blake3::hash(&large).as_bytes());
+        assert_eq!(big.version, index.get_metadata("big.pdf").unwrap().version);
+        let mut content = Vec::new();
+        assert_eq!(RemoteBackend::get(&*b, big, &mut content).await.unwrap(), large.len() as u64);
+        assert!(content == large);
+        let mut content = Vec::new();
+        RemoteBackend::get(&*b, &listed.files["notes/hello.md"], &mut content).await.unwrap();
+        assert_eq!(content, b"hello");
+        // Nothing changed since, so the same commit comes back.
+        assert_eq!(b.list().await.unwrap().0, commit);
+
+        // A new revision replaces the asset rather than adding to it.
+        large[0] ^= 1;
+        a.push(vec![(edit(&mut index, "big.pdf", &large), &large[..])], Vec::new()).await.unwrap();
+        assert_eq!(github.assets().len(), 1);
+        let (_, listed) = b.list().await.unwrap();
+        let mut content = Vec::new();
+        RemoteBackend::get(&*b, &listed.files["big.pdf"], &mut content).await.unwrap();
+        assert!(content == large);
+
+        let hello = listed.files["notes/hello.md"].object.clone().unwrap();
+        a.push(vec![(edit(&mut index, "copy.md", b"hello"), &b"hello"[..])], Vec::new()).await.unwrap();
+        let objects = github.paths().len();
+        index.remove_file("notes/hello.md", chrono::Utc::now().timestamp() as u64).unwrap();
+        let tombstone = index.get_metadata("notes/hello.md").unwrap().clone();
+        a.push(Vec::<(FileMetadata, &[u8])>::new(), vec![tombstone]).await.unwrap();
+        let (_, listed) = b.list().await.unwrap();
+        assert!(listed.files["notes/hello.md"].tombstone.is_some());
+        // Its manifest goes out in the same commit; the chunk copy.md
+        // shares stays.
+        assert!(!github.paths().contains(&hello));
+        assert_eq!(github.paths().len(), objects - 1);
+        let mut content = Vec::new();
+        RemoteBackend::get(&*b, &listed.files["copy.md"], &mut content).await.unwrap();
+        assert_eq!(content, b"hello");
+        index.remove_file("copy.md", chrono::Utc::now().timestamp() as u64).unwrap();
+        let tombstone = index.get_metadata("copy.md").unwrap().clone();
+        a.push(Vec::<(FileMetadata, &[u8])>::new(), vec![tombstone]).await.unwrap();
+        assert_eq!(github.paths().len(), objects - 3);
+
+        // Content changed since it was indexed is left out, and its chunks
+        // don't go in with the next commit either.
+        let objects = github.paths().len();
+        let stale = edit(&mut index, "stale.md", b"as indexed");
+        a.push(vec![(stale, &b"changed since"[..])], Vec::new()).await.unwrap();
+        a.push(vec![(edit(&mut index, "other.md", b"other"), &b"other"[..])], Vec::new()).await.unwrap();
+        // Its manifest and one chunk.
+        assert_eq!(github.paths().len(), objects + 2);
+    }
+
+    #[tokio::test]
+    async fn manifest_swaps_fail_once_the_branch_moves() {
+        let (_github, server) = MockGitHub::start().await;
+        let (a, b) = (device(server.uri()), Remote::new(device(server.uri())));
+        let mut index = VaultIndexer::new();
+
+        let (version, listed) = a.list().await.unwrap();
+        b.push(vec![(edit(&mut index, "b.md", b"from b"), &b"from b"[..])], Vec::new()).await.unwrap();
+        assert!(!a.swap_manifest(&version, &listed, "stale").await.unwrap());
+        assert_eq!(a.list().await.unwrap().1.files.len(), 1);
+
+        let (version, _) = a.list().await.unwrap();
+        assert!(a.swap_manifest(&version, &listed, "reset").await.unwrap());
+        assert!(a.list().await.unwrap().1.files.is_empty());
+    }
+
+    #[tokio::test]
+    async fn chunks_collected_during_a_push_are_uploaded_again() {
+        let (_github, server) = MockGitHub::start().await;
+        let (a, b) = (Remote::new(device(server.uri())), device(server.uri()));
+        let mut index = VaultIndexer::new();
+
+        a.push(vec![(edit(&mut index, "x.md", b"shared"), &b"shared"[..])], Vec::new()).await.unwrap();
+        b.list().await.unwrap();
+        index.remove_file("x.md", 1).unwrap();
+        let tombstone = index.get_metadata("x.md").unwrap().clone();
+        a.push(Vec::<(FileMetadata, &[u8])>::new(), vec![tombstone]).await.unwrap();
+
+        // b skips the chunk as it last saw the branch holding it.
+        let y = edit(&mut index, "y.md", b"shared");
+        let remote = Remote::new(b.clone());
+        assert!(remote.push(vec![(y.clone(), &b"shared"[..])], Vec::new()).await.is_err());
+        remote.push(vec![(y, &b"shared"[..])], Vec::new()).await.unwrap();
+        let (_, listed) = b.list().await.unwrap();
+        let mut content = Vec::new();
+        RemoteBackend::get(&*b, &listed.files["y.md"], &mut content).await.unwrap();
+        assert_eq!(content, b"shared");
+    }
+
+    #[tokio::test]
+    async fn orphaned_assets_are_collected() {
+        let (github, server) = MockGitHub::start().await;
+        let mut index = VaultIndexer::new();
+        let mut large = vec![0u8; 2 * 1024 * 1024];
+        rand::thread_rng().fill_bytes(&mut large);
+
+        // Uploaded by a push that never landed.
+        RemoteBackend::put(&*device(server.uri()), &mut &large[..], large.len() as u64).await.unwrap();
+        assert_eq!(github.assets().len(), 1);
+
+        let b = device(server.uri());
+        large[0] ^= 1;
+        Remote::new(b.clone()).push(vec![(edit(&mut index, "big.pdf", &large), &large[..])], Vec::new()).await.unwrap();
+        assert_eq!(github.assets().len(), 1);
+        let (_, listed) = b.list().await.unwrap();
+        let mut content = Vec::new();
+        RemoteBackend::get(&*b, &listed.files["big.pdf"], &mut content).await.unwrap();
+        assert!(content == large);
+    }
+
+    #[tokio::test]
+    async fn branches_an_earlier_version_wrote_are_refused() {
+        let (github, server) = MockGitHub::start().await;
+        github.commit_file("README.md", b"# Vault");
+        assert!(GitHubStorage::published_key_params(config(server.uri())).await.unwrap().is_none());
+
+        // What an earlier version uploaded through the contents API.
+        let stored = serde_json::json!({ "ciphertext": vec![1u8; 40], "nonce": [2u8; 24] });
+        let encoded = general_purpose::STANDARD.encode(serde_json::to_vec(&stored).unwrap());
+        github.commit_file("notes/hello.md", encoded.as_bytes());
+        let error = GitHubStorage::published_key_params(config(server.uri())).await.unwrap_err();
+        assert!(error.is::<UnmigratedStore>());
+    }
+
+    #[tokio::test]
+    async fn asset_sizes_are_padded_to_buckets() {
+        let (github, server) = MockGitHub::start().await;
+        let a = device(server.uri());
+        for size in [3_000_000, 3_000_100] {
+            let large = vec![7u8; size];
+            RemoteBackend::put(&*a, &mut &large[..], size as u64).await.unwrap();
+        }
+
+        let lens = github.asset_lens();
+        assert_eq!(lens.len(), 2);
+        assert_eq!(lens[0], lens[1]);
+        assert_eq!(lens[0] as u64, sealed_stream_len(3_000_000));
+    }
+
+    #[tokio::test]
+    async fn key_params_are_published_for_devices_without_the_key() {
+        let (_github, server) = MockGitHub::start().await;
+        let a = device(server.uri());
+        let cheap = KdfParams {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let (params, _) = KeyParams::generate("passphrase", cheap).unwrap();
+
+        assert!(a.key_params().await.unwrap().is_none());
+        a.publish_key_params(&params).await.unwrap();
+        let mut index = VaultIndexer::new();
+        Remote::new(a.clone()).push(vec![(edit(&mut index, "a.md", b"from a"), &b"from a"[..])], Vec::new()).await.unwrap();
+
+        let published = GitHubStorage::published_key_params(config(server.uri())).await.unwrap();
+        assert_eq!(published, Some(params));
+    }
+
+    #[test]
+    fn only_races_are_retried_as_conflicts() {
+        let refused = |status: u16, message: &str| {
+            anyhow::Error::new(ApiError {
+                status: StatusCode::from_u16(status).unwrap(),
+                message: message.to_string(),
+                limited_until: None,
+            })
+        };
+        assert!(is_conflict(&refused(409, "is at 1234 but expected 5678")));
+        assert!(is_conflict(&refused(422, "Update is not a fast forward")));
+        assert!(!is_conflict(&refused(422, "Validation Failed")));
+        assert!(!is_conflict(&refused(404, "Not Found")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_wait_out_rate_limits() {
+        let (github, server) = MockGitHub::start().await;
+        let device = device(server.uri());
+        let remote = Remote::new(device.clone());
+        let mut index = VaultIndexer::new();
+
+        github.limit(2);
+        let started = tokio::time::Instant::now();
+        let note = edit(&mut index, "note.md", b"hello");
+        remote.push(vec![(note, &b"hello"[..])], Vec::new()).await.unwrap();
+        assert!(started.elapsed() >= std::time::Duration::from_secs(2));
+        assert_eq!(device.limited_until(), None);
+
+        // More refusals than are retried fail the request.
+        github.limit(10);
+        let error = device.list().await.unwrap_err();
+        assert!(error.to_string().contains("rate limit"), "{}", error);
+        assert!(device.limited_until().is_some());
+        github.limit(0);
+        device.throttle.ready(false).await;
+        assert_eq!(device.list().await.unwrap().1.files.len(), 1);
     }
 }
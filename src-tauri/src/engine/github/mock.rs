@@ -0,0 +1,301 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use base64::{engine::general_purpose, Engine as _};
+use serde::Deserialize;
+use serde_json::json;
+use wiremock::http::Method;
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+pub const OWNER: &str = "owner";
+pub const REPO: &str = "vault";
+pub const BRANCH: &str = "main";
+
+/// An in-memory stand-in for the parts of the GitHub git data and releases
+/// APIs that [`GitHubStorage`](super::GitHubStorage) uses: a repository
+/// with one branch, starting at an empty commit, and no releases.
+#[derive(Clone)]
+pub struct MockGitHub {
+    repo: Arc<Mutex<Repo>>,
+}
+
+#[derive(Default)]
+struct Repo {
+    blobs: HashMap<String, Vec<u8>>,
+    /// Trees are kept flat: every blob by its full path.
+    trees: HashMap<String, BTreeMap<String, String>>,
+    commits: HashMap<String, Commit>,
+    head: String,
+    /// Where the server listens, for the upload URLs it hands out.
+    uri: String,
+    /// Id of the one release, once created.
+    release: Option<u64>,
+    /// Release assets by id: name and content.
+    assets: BTreeMap<u64, (String, Vec<u8>)>,
+    next_id: u64,
+    /// How many of the next requests are refused for a secondary rate limit.
+    limited: usize,
+}
+
+struct Commit {
+    tree: String,
+    parents: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct NewTree {
+    base_tree: Option<String>,
+    tree: Vec<NewTreeEntry>,
+}
+
+#[derive(Deserialize)]
+struct NewTreeEntry {
+    path: String,
+    sha: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NewCommit {
+    tree: String,
+    parents: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct RefUpdate {
+    sha: String,
+    force: bool,
+}
+
+fn object_id(kind: &str, content: &[u8]) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(kind.as_bytes());
+    hasher.update(content);
+    hasher.finalize().to_hex()[..40].to_string()
+}
+
+impl MockGitHub {
+    /// Serve a new repository; returns it and the API URL to use.
+    pub async fn start() -> (Self, MockServer) {
+        let mut repo = Repo::default();
+        let tree = object_id("tree", b"");
+        repo.trees.insert(tree.clone(), BTreeMap::new());
+        repo.head = repo.add_commit(tree, Vec::new());
+
+        let mock = Self {
+            repo: Arc::new(Mutex::new(repo)),
+        };
+        let server = MockServer::start().await;
+        mock.repo.lock().unwrap().uri = server.uri();
+        Mock::given(wiremock::matchers::any())
+            .respond_with(mock.clone())
+            .mount(&server)
+            .await;
+        (mock, server)
+    }
+
+    /// Names of the release's assets.
+    pub fn assets(&self) -> Vec<String> {
+        self.repo.lock().unwrap().assets.values().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// Uploaded sizes of the release's assets.
+    pub fn asset_lens(&self) -> Vec<usize> {
+        self.repo.lock().unwrap().assets.values().map(|(_, content)| content.len()).collect()
+    }
+
+    /// Refuse the next `requests` requests for a secondary rate limit, each
+    /// asking for a second's wait.
+    pub fn limit(&self, requests: usize) {
+        self.repo.lock().unwrap().limited = requests;
+    }
+
+    /// Commit `content` at `path` on the branch, as another client would.
+    pub fn commit_file(&self, path: &str, content: &[u8]) {
+        let mut repo = self.repo.lock().unwrap();
+        let sha = object_id("blob", content);
+        repo.blobs.insert(sha.clone(), content.to_vec());
+        let mut tree = repo.trees[&repo.commits[&repo.head].tree].clone();
+        tree.insert(path.to_string(), sha);
+        let tree_sha = object_id("tree", format!("{:?}", tree).as_bytes());
+        repo.trees.insert(tree_sha.clone(), tree);
+        let parent = repo.head.clone();
+        repo.head = repo.add_commit(tree_sha, vec![parent]);
+    }
+
+    /// Paths of every blob at the branch head.
+    pub fn paths(&self) -> Vec<String> {
+        let repo = self.repo.lock().unwrap();
+        let tree = &repo.commits[&repo.head].tree;
+        repo.trees[tree].keys().cloned().collect()
+    }
+}
+
+impl Repo {
+    fn add_commit(&mut self, tree: String, parents: Vec<String>) -> String {
+        let sha = object_id("commit", format!("{} {:?} {}", tree, parents, self.commits.len()).as_bytes());
+        self.commits.insert(sha.clone(), Commit { tree, parents });
+        sha
+    }
+
+    fn handle_release(&mut self, method: &Method, route: &[&str], request: &Request) -> ResponseTemplate {
+        let uri = &self.uri;
+        let release = |id: u64| {
+            json!({
+                "id": id,
+                "tag_name": "oversync-objects",
+                "upload_url": format!("{}/uploads/repos/{}/{}/releases/{}/assets{{?name,label}}", uri, OWNER, REPO, id),
+            })
+        };
+        match (method.as_str(), route) {
+            ("GET", ["tags", _]) => match self.release {
+                Some(id) => ok(release(id)),
+                None => not_found(),
+            },
+            ("POST", []) => {
+                self.next_id += 1;
+                self.release = Some(self.next_id);
+                created(release(self.next_id))
+            }
+            // Every asset is listed on the first page, as uploaded long ago.
+            ("GET", [id, "assets"]) if Some(id.parse::<u64>().unwrap()) == self.release => {
+                let first = request.url.query_pairs().all(|(key, value)| key != "page" || value == "1");
+                let assets: Vec<_> = self
+                    .assets
+                    .iter()
+                    .filter(|_| first)
+                    .map(|(id, (name, _))| json!({ "id": id, "name": name, "created_at": "2000-01-01T00:00:00Z" }))
+                    .collect();
+                ok(json!(assets))
+            }
+            ("GET", ["assets", id]) => match self.assets.get(&id.parse::<u64>().unwrap()) {
+                Some((_, content)) => ResponseTemplate::new(200).set_body_bytes(content.clone()),
+                None => not_found(),
+            },
+            ("DELETE", ["assets", id]) => match self.assets.remove(&id.parse::<u64>().unwrap()) {
+                Some(_) => ResponseTemplate::new(204),
+                None => not_found(),
+            },
+            ("POST", [id, "assets"]) if Some(id.parse::<u64>().unwrap()) == self.release => {
+                let name = request.url.query_pairs().find(|(key, _)| key == "name").unwrap().1.to_string();
+                self.next_id += 1;
+                self.assets.insert(self.next_id, (name.clone(), request.body.clone()));
+                created(json!({ "id": self.next_id, "name": name }))
+            }
+            _ => not_found(),
+        }
+    }
+
+    fn handle(&mut self, method: &Method, route: &[&str], request: &Request) -> ResponseTemplate {
+        match (method.as_str(), route) {
+            ("GET", ["ref", "heads", BRANCH]) => ok(json!({
+                "ref": format!("refs/heads/{}", BRANCH),
+                "object": { "type": "commit", "sha": self.head },
+            })),
+            ("GET", ["commits", sha]) => match self.commits.get(*sha) {
+                Some(commit) => ok(json!({
+                    "sha": sha,
+                    "tree": { "sha": commit.tree },
+                    "parents": commit.parents.iter().map(|sha| json!({ "sha": sha })).collect::<Vec<_>>(),
+                })),
+                None => not_found(),
+            },
+            ("GET", ["trees", sha]) => match self.trees.get(*sha) {
+                Some(tree) => ok(json!({
+                    "sha": sha,
+                    "tree": tree
+                        .iter()
+                        .map(|(path, sha)| json!({ "path": path, "mode": "100644", "type": "blob", "sha": sha }))
+                        .collect::<Vec<_>>(),
+                    "truncated": false,
+                })),
+                None => not_found(),
+            },
+            ("GET", ["blobs", sha]) => match self.blobs.get(*sha) {
+                // GitHub wraps the base64 in lines of 60 characters.
+                Some(content) => ok(json!({
+                    "sha": sha,
+                    "content": general_purpose::STANDARD
+                        .encode(content)
+                        .as_bytes()
+                        .chunks(60)
+                        .map(|line| std::str::from_utf8(line).unwrap())
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                    "encoding": "base64",
+                })),
+                None => not_found(),
+            },
+            ("POST", ["blobs"]) => {
+                let body: serde_json::Value = request.body_json().unwrap();
+                let content = general_purpose::STANDARD.decode(body["content"].as_str().unwrap()).unwrap();
+                let sha = object_id("blob", &content);
+                self.blobs.insert(sha.clone(), content);
+                created(json!({ "sha": sha }))
+            }
+            ("POST", ["trees"]) => {
+                let body: NewTree = request.body_json().unwrap();
+                let mut tree = match body.base_tree {
+                    Some(base) => self.trees[&base].clone(),
+                    None => BTreeMap::new(),
+                };
+                for entry in body.tree {
+                    match entry.sha {
+                        Some(sha) => tree.insert(entry.path, sha),
+                        None => tree.remove(&entry.path),
+                    };
+                }
+                let sha = object_id("tree", format!("{:?}", tree).as_bytes());
+                self.trees.insert(sha.clone(), tree);
+                created(json!({ "sha": sha }))
+            }
+            ("POST", ["commits"]) => {
+                let body: NewCommit = request.body_json().unwrap();
+                let sha = self.add_commit(body.tree, body.parents);
+                created(json!({ "sha": sha }))
+            }
+            ("PATCH", ["refs", "heads", BRANCH]) => {
+                let body: RefUpdate = request.body_json().unwrap();
+                if !body.force && !self.commits[&body.sha].parents.contains(&self.head) {
+                    return ResponseTemplate::new(422).set_body_json(json!({ "message": "Update is not a fast forward" }));
+                }
+                self.head = body.sha;
+                ok(json!({ "ref": format!("refs/heads/{}", BRANCH), "object": { "sha": self.head } }))
+            }
+            _ => not_found(),
+        }
+    }
+}
+
+impl Respond for MockGitHub {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let path = request.url.path();
+        let path = path.strip_prefix("/uploads").unwrap_or(path);
+        let Some(route) = path.strip_prefix(&format!("/repos/{}/{}/", OWNER, REPO)) else {
+            return not_found();
+        };
+        let route: Vec<&str> = route.split('/').collect();
+        let mut repo = self.repo.lock().unwrap();
+        if repo.limited > 0 {
+            repo.limited -= 1;
+            return ResponseTemplate::new(403)
+                .insert_header("retry-after", "1")
+                .set_body_json(json!({ "message": "You have exceeded a secondary rate limit." }));
+        }
+        match route.as_slice() {
+            ["git", route @ ..] => repo.handle(&request.method, route, request),
+            ["releases", route @ ..] => repo.handle_release(&request.method, route, request),
+            _ => not_found(),
+        }
+    }
+}
+
+fn ok(body: serde_json::Value) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(body)
+}
+
+fn created(body: serde_json::Value) -> ResponseTemplate {
+    ResponseTemplate::new(201).set_body_json(body)
+}
+
+fn not_found() -> ResponseTemplate {
+    ResponseTemplate::new(404).set_body_json(json!({ "message": "Not Found" }))
+}
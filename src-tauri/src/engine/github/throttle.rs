@@ -0,0 +1,167 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use tokio::time::Instant;
+
+/// Wait after the first secondary rate limit that doesn't say how long to
+/// wait, doubled for each one after it up to `MAX_BACKOFF`.
+const MIN_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Holds requests back while GitHub's rate limits are exhausted, as learned
+/// from responses: the `X-RateLimit-*` headers every response carries, and
+/// `Retry-After` or the error message on a refused one. A secondary limit
+/// met by a write, which GitHub mostly imposes on creating content, only
+/// holds back writes.
+#[derive(Default)]
+pub struct Throttle {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// No request is sent before this.
+    until: Option<Instant>,
+    /// No write is sent before this.
+    writes_until: Option<Instant>,
+    /// Rate limited responses in a row, for the backoff.
+    strikes: u32,
+}
+
+impl Throttle {
+    /// When writes may be sent again, while they are held back. Reads may
+    /// be sent sooner.
+    pub fn limited_until(&self) -> Option<DateTime<Utc>> {
+        let state = self.state.lock().unwrap();
+        state.until.max(state.writes_until).and_then(wall_clock)
+    }
+
+    /// When requests that write, or only read if `write` is false, may be
+    /// sent, while they are held back.
+    fn held_until(&self, write: bool) -> Option<Instant> {
+        let state = self.state.lock().unwrap();
+        let until = if write { state.until.max(state.writes_until) } else { state.until };
+        until.filter(|until| *until > Instant::now())
+    }
+
+    /// Wait until a request that writes, if `write`, or reads may be sent.
+    pub async fn ready(&self, write: bool) {
+        while let Some(until) = self.held_until(write) {
+            tokio::time::sleep_until(until).await;
+        }
+    }
+
+    /// Learn from a response to a request that wrote, if `write`, with
+    /// `status`, `headers` and, if it failed, error `message`. Returns when
+    /// such requests may be sent again if it was refused for exceeding a
+    /// rate limit.
+    pub fn observe(&self, write: bool, status: StatusCode, headers: &HeaderMap, message: &str) -> Option<DateTime<Utc>> {
+        let header = |name: &str| -> Option<i64> { headers.get(name)?.to_str().ok()?.trim().parse().ok() };
+        let remaining = header("x-ratelimit-remaining");
+        let reset = header("x-ratelimit-reset")
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+            .map(|reset| Instant::now() + (reset - Utc::now()).to_std().unwrap_or_default());
+        let retry_after = header("retry-after");
+        let now = Instant::now();
+
+        let mut state = self.state.lock().unwrap();
+        let limited = matches!(status, StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS)
+            && (retry_after.is_some() || remaining == Some(0) || message.contains("rate limit"));
+        if !limited {
+            if status.is_success() {
+                state.strikes = 0;
+            }
+            // The hourly quota is spent: hold the next request back rather
+            // than have it refused.
+            if remaining == Some(0) {
+                state.until = state.until.max(reset);
+            }
+            return None;
+        }
+
+        let until = match (retry_after, reset) {
+            (Some(secs), _) => now + Duration::from_secs(secs.max(0) as u64),
+            (None, Some(reset)) if remaining == Some(0) => reset,
+            _ => now + backoff(state.strikes),
+        };
+        state.strikes += 1;
+        if write && remaining != Some(0) {
+            state.writes_until = state.writes_until.max(Some(until));
+        } else {
+            state.until = state.until.max(Some(until));
+        }
+        drop(state);
+        self.held_until(write).and_then(wall_clock)
+    }
+}
+
+/// When `instant` comes by the wall clock, if it is still to come.
+fn wall_clock(instant: Instant) -> Option<DateTime<Utc>> {
+    let left = instant.checked_duration_since(Instant::now()).filter(|left| !left.is_zero())?;
+    Some(Utc::now() + chrono::Duration::from_std(left).ok()?)
+}
+
+/// Wait after `strikes` rate limited responses in a row, with up to a
+/// quarter more at random so devices sharing a token don't retry together.
+fn backoff(strikes: u32) -> Duration {
+    let wait = MIN_BACKOFF.saturating_mul(2u32.saturating_pow(strikes)).min(MAX_BACKOFF);
+    let jitter = rand::thread_rng().gen_range(0..=wait.as_millis() as u64 / 4);
+    wait + Duration::from_millis(jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Throttle, MAX_BACKOFF, MIN_BACKOFF};
+    use chrono::Utc;
+    use reqwest::header::HeaderMap;
+    use reqwest::StatusCode;
+
+    fn headers(pairs: &[(&'static str, String)]) -> HeaderMap {
+        pairs.iter().map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap())).collect()
+    }
+
+    #[test]
+    fn limits_are_read_from_headers_or_backed_off() {
+        let throttle = Throttle::default();
+        assert_eq!(throttle.observe(false, StatusCode::OK, &headers(&[("x-ratelimit-remaining", "10".into())]), ""), None);
+        assert_eq!(throttle.limited_until(), None);
+        // Refused for another reason.
+        assert_eq!(throttle.observe(false, StatusCode::FORBIDDEN, &HeaderMap::new(), "Resource not accessible"), None);
+
+        // The last request of the hour succeeds, but holds back the next.
+        let reset = Utc::now().timestamp() + 600;
+        let spent = headers(&[("x-ratelimit-remaining", "0".into()), ("x-ratelimit-reset", reset.to_string())]);
+        assert_eq!(throttle.observe(false, StatusCode::OK, &spent, ""), None);
+        assert!((throttle.limited_until().unwrap().timestamp() - reset).abs() <= 1);
+
+        let throttle = Throttle::default();
+        let retry = headers(&[("retry-after", "30".into())]);
+        let until = throttle.observe(false, StatusCode::FORBIDDEN, &retry, "").unwrap();
+        assert!((until - Utc::now()).num_seconds() >= 29);
+
+        // Secondary limits without a wait back off exponentially.
+        let throttle = Throttle::default();
+        let message = "You have exceeded a secondary rate limit.";
+        let mut last = chrono::Duration::zero();
+        for strikes in 0..8 {
+            let wait = throttle.observe(false, StatusCode::FORBIDDEN, &HeaderMap::new(), message).unwrap() - Utc::now();
+            let expected = MIN_BACKOFF.saturating_mul(2u32.pow(strikes)).min(MAX_BACKOFF).as_secs() as i64;
+            assert!(wait.num_seconds() >= expected - 1 && wait.num_seconds() <= expected * 5 / 4);
+            assert!(wait >= last - chrono::Duration::seconds(1));
+            last = wait;
+        }
+        // A success starts the backoff over.
+        throttle.observe(false, StatusCode::OK, &HeaderMap::new(), "");
+        assert_eq!(throttle.state.lock().unwrap().strikes, 0);
+
+        // A secondary limit met by a write only holds back writes.
+        let throttle = Throttle::default();
+        assert!(throttle.observe(true, StatusCode::FORBIDDEN, &HeaderMap::new(), message).is_some());
+        assert!(throttle.held_until(false).is_none());
+        assert!(throttle.held_until(true).is_some());
+        assert!(throttle.limited_until().is_some());
+    }
+}
@@ -0,0 +1,106 @@
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+/// Shared rules at the vault root, synced like any other file.
+pub const IGNORE_FILE: &str = ".oversyncignore";
+
+/// Device-only rules in the app data directory, applied last so they can
+/// re-include (`!pattern`) anything the defaults or the vault file exclude.
+pub const LOCAL_IGNORE_FILE: &str = "oversyncignore.local";
+
+/// Built-in rules tuned for Obsidian vaults.
+const DEFAULT_RULES: &[&str] = &[
+    // Per-device window layout, rewritten on every pane change.
+    ".obsidian/workspace.json",
+    ".obsidian/workspace-mobile.json",
+    ".trash/",
+    ".git/",
+    // OS and editor litter.
+    ".DS_Store",
+    "Thumbs.db",
+    "desktop.ini",
+    "*.swp",
+    "*.tmp",
+    "*~",
+    ".#*",
+    "*.oversync-tmp",
+];
+
+/// gitignore-syntax matcher deciding which vault paths are synced.
+#[derive(Clone)]
+pub struct IgnoreRules {
+    matcher: Gitignore,
+}
+
+impl Default for IgnoreRules {
+    fn default() -> Self {
+        Self::from_rules(Path::new(""), &[]).expect("built-in ignore rules are valid")
+    }
+}
+
+impl IgnoreRules {
+    /// Built-in defaults, then `extra` (the `.obsidian/` category rules),
+    /// then `.oversyncignore` from the vault, then the device's overrides at
+    /// `local_path`. Missing files are skipped.
+    pub fn load(vault_path: &Path, extra: &[String], local_path: &Path) -> Result<Self> {
+        let mut lines = extra.to_vec();
+        for path in [vault_path.join(IGNORE_FILE), local_path.to_path_buf()] {
+            match std::fs::read_to_string(&path) {
+                Ok(content) => lines.extend(content.lines().map(str::to_string)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Self::from_rules(vault_path, &lines)
+    }
+
+    /// Defaults followed by `lines`; later lines win, as in `.gitignore`.
+    pub fn from_rules(root: &Path, lines: &[String]) -> Result<Self> {
+        let mut builder = GitignoreBuilder::new(root);
+        for line in DEFAULT_RULES.iter().copied().chain(lines.iter().map(String::as_str)) {
+            builder.add_line(None::<PathBuf>, line)?;
+        }
+        Ok(Self { matcher: builder.build()? })
+    }
+
+    /// Whether a vault-relative path, or any folder above it, is ignored.
+    pub fn is_ignored(&self, relative_path: &Path, is_dir: bool) -> bool {
+        if relative_path.has_root() {
+            return false;
+        }
+        self.matcher
+            .matched_path_or_any_parents(relative_path, is_dir)
+            .is_ignore()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IgnoreRules;
+    use std::path::Path;
+
+    #[test]
+    fn defaults_skip_obsidian_workspace_and_trash() {
+        let rules = IgnoreRules::default();
+
+        assert!(rules.is_ignored(Path::new(".obsidian/workspace.json"), false));
+        assert!(rules.is_ignored(Path::new(".trash/old note.md"), false));
+        assert!(rules.is_ignored(Path::new(".git/objects/ab/cdef"), false));
+        assert!(rules.is_ignored(Path::new("notes/.note.md.swp"), false));
+        assert!(!rules.is_ignored(Path::new(".obsidian/hotkeys.json"), false));
+        assert!(!rules.is_ignored(Path::new("notes/daily.md"), false));
+    }
+
+    #[test]
+    fn later_rules_override_defaults() {
+        let lines = vec![
+            "drafts/".to_string(),
+            "!.obsidian/workspace.json".to_string(),
+        ];
+        let rules = IgnoreRules::from_rules(Path::new(""), &lines).unwrap();
+
+        assert!(rules.is_ignored(Path::new("drafts/idea.md"), false));
+        assert!(!rules.is_ignored(Path::new(".obsidian/workspace.json"), false));
+    }
+}
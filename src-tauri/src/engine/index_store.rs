@@ -0,0 +1,93 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use anyhow::Result;
+use redb::{Database, ReadableTable, TableDefinition};
+use crate::engine::storage::FileMetadata;
+
+/// path -> JSON-encoded `FileMetadata`, so new fields only need `serde(default)`.
+const FILES: TableDefinition<&str, &[u8]> = TableDefinition::new("files");
+
+/// Ids of every device we have synced with, which must all acknowledge a
+/// tombstone before it can be dropped.
+const DEVICES: TableDefinition<&str, ()> = TableDefinition::new("devices");
+
+/// On-disk copy of the vault index, kept in the app data directory.
+///
+/// Only the metadata map is stored; the MST is rebuilt from it on load.
+pub struct IndexStore {
+    db: Database,
+}
+
+impl IndexStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let db = Database::create(path)?;
+        let txn = db.begin_write()?;
+        txn.open_table(FILES)?;
+        txn.open_table(DEVICES)?;
+        txn.commit()?;
+
+        Ok(Self { db })
+    }
+
+    pub fn load(&self) -> Result<HashMap<String, FileMetadata>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(FILES)?;
+
+        let mut metadata = HashMap::new();
+        for entry in table.iter()? {
+            let (path, value) = entry?;
+            let meta: FileMetadata = serde_json::from_slice(value.value())?;
+            metadata.insert(path.value().to_string(), meta);
+        }
+
+        Ok(metadata)
+    }
+
+    pub fn put(&self, meta: &FileMetadata) -> Result<()> {
+        let value = serde_json::to_vec(meta)?;
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(FILES)?;
+            table.insert(meta.path.as_str(), value.as_slice())?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub fn load_devices(&self) -> Result<BTreeSet<String>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(DEVICES)?;
+
+        let mut devices = BTreeSet::new();
+        for entry in table.iter()? {
+            let (device, _) = entry?;
+            devices.insert(device.value().to_string());
+        }
+
+        Ok(devices)
+    }
+
+    pub fn add_device(&self, device: &str) -> Result<()> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(DEVICES)?;
+            table.insert(device, ())?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub fn delete(&self, path: &str) -> Result<()> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(FILES)?;
+            table.remove(path)?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Serialize, Deserialize};
+use zeroize::Zeroizing;
+use crate::engine::encryption::Encryptor;
+
+/// Where the vault's key parameters are kept, in the app data directory.
+pub const KEY_PARAMS_FILE: &str = "key_params.json";
+
+/// Argon2id memory costs above this are clamped to it, so parameters read
+/// from a remote can't exhaust memory: 1 GiB.
+const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+
+/// Argon2id pass counts above this are clamped to it.
+const MAX_ITERATIONS: u32 = 16;
+
+/// Argon2id cost settings, stored with the salt so every device derives
+/// the same key.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// 64 MiB, 3 passes: a fraction of a second on a phone.
+    fn default() -> Self {
+        Self {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+/// Everything but the passphrase needed to derive a vault key. Not secret;
+/// it is copied to each device that joins the vault.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KeyParams {
+    pub salt: [u8; 16],
+    pub kdf: KdfParams,
+    /// MAC of a constant under the derived key, so a wrong passphrase is
+    /// caught before anything is decrypted with it.
+    pub check: [u8; 32],
+    /// Key version; bumped by every rotation.
+    #[serde(default)]
+    pub epoch: u32,
+    /// Keys of earlier epochs, sealed under this one, so data not yet
+    /// re-encrypted stays readable with only the newest passphrase.
+    #[serde(default)]
+    pub previous: Vec<WrappedKey>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WrappedKey {
+    pub epoch: u32,
+    pub sealed: Vec<u8>,
+}
+
+/// A vault encryption key, wiped from memory when dropped.
+#[derive(Clone)]
+pub struct VaultKey(Zeroizing<[u8; 32]>);
+
+impl VaultKey {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    fn check_value(&self) -> [u8; 32] {
+        blake3::keyed_hash(self.as_bytes(), b"oversync key check").into()
+    }
+}
+
+impl From<[u8; 32]> for VaultKey {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(Zeroizing::new(bytes))
+    }
+}
+
+/// Every key the vault has used, by epoch, the newest being current.
+#[derive(Clone)]
+pub struct KeyRing {
+    keys: BTreeMap<u32, VaultKey>,
+}
+
+impl KeyRing {
+    pub fn current(&self) -> (u32, &VaultKey) {
+        let (epoch, key) = self.keys.last_key_value().expect("a key ring is never empty");
+        (*epoch, key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = (u32, &VaultKey)> {
+        self.keys.iter().map(|(epoch, key)| (*epoch, key))
+    }
+}
+
+impl From<VaultKey> for KeyRing {
+    fn from(key: VaultKey) -> Self {
+        Self {
+            keys: BTreeMap::from([(0, key)]),
+        }
+    }
+}
+
+impl From<[u8; 32]> for KeyRing {
+    fn from(bytes: [u8; 32]) -> Self {
+        VaultKey::from(bytes).into()
+    }
+}
+
+impl KeyParams {
+    /// Fresh parameters for a new vault, checked against `passphrase`.
+    pub fn generate(passphrase: &str, kdf: KdfParams) -> Result<(Self, VaultKey)> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let mut params = Self {
+            salt,
+            kdf,
+            check: [0; 32],
+            epoch: 0,
+            previous: Vec::new(),
+        };
+        let key = params.stretch(passphrase)?;
+        params.check = key.check_value();
+        Ok((params, key))
+    }
+
+    /// Derive the key for `passphrase`, failing if it isn't this vault's.
+    pub fn derive(&self, passphrase: &str) -> Result<VaultKey> {
+        let key = self.stretch(passphrase)?;
+        if key.check_value() != self.check {
+            return Err(anyhow!("wrong passphrase for this vault"));
+        }
+        Ok(key)
+    }
+
+    /// Derive the current key and unwrap the earlier ones.
+    pub fn unlock(&self, passphrase: &str) -> Result<KeyRing> {
+        let key = self.derive(passphrase)?;
+        let unwrapper = Encryptor::new(key.as_bytes());
+        let mut keys = BTreeMap::new();
+        for wrapped in &self.previous {
+            let bytes = Zeroizing::new(unwrapper.open(&wrapped.sealed)?);
+            let bytes: [u8; 32] = bytes.as_slice().try_into()?;
+            keys.insert(wrapped.epoch, VaultKey::from(bytes));
+        }
+        keys.insert(self.epoch, key);
+        Ok(KeyRing { keys })
+    }
+
+    /// Parameters for the epoch after `ring`'s under `passphrase`, carrying
+    /// every key in `ring` wrapped under the new one.
+    pub fn rotate(ring: &KeyRing, passphrase: &str, kdf: KdfParams) -> Result<(Self, KeyRing)> {
+        let (mut params, key) = Self::generate(passphrase, kdf)?;
+        params.epoch = ring.current().0 + 1;
+
+        let wrapper = Encryptor::new(key.as_bytes());
+        let mut keys = BTreeMap::new();
+        for (epoch, old) in ring.keys() {
+            params.previous.push(WrappedKey {
+                epoch,
+                sealed: wrapper.seal(old.as_bytes())?,
+            });
+            keys.insert(epoch, VaultKey::from(*old.as_bytes()));
+        }
+        keys.insert(params.epoch, key);
+        Ok((params, KeyRing { keys }))
+    }
+
+    /// Costs beyond the limits are clamped rather than refused; the key then
+    /// derived fails the check value, as for any other tampered parameter.
+    fn stretch(&self, passphrase: &str) -> Result<VaultKey> {
+        let memory_kib = self.kdf.memory_kib.min(MAX_MEMORY_KIB);
+        let iterations = self.kdf.iterations.min(MAX_ITERATIONS);
+        let params = Params::new(memory_kib, iterations, self.kdf.parallelism, Some(32))
+            .map_err(|e| anyhow!("invalid KDF parameters: {}", e))?;
+        let mut key = Zeroizing::new([0u8; 32]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, key.as_mut())
+            .map_err(|e| anyhow!("key derivation failed: {}", e))?;
+        Ok(VaultKey(key))
+    }
+
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        match std::fs::read(path) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Unlock the vault key stored under `data_dir`.
+///
+/// A device joining an existing vault passes the vault's `imported` params,
+/// such as those published on a remote. The saved and imported params are
+/// tried newest epoch first, and the first to unlock under `passphrase` is
+/// saved, so a corrupt or forged entry of a later epoch falls back to the
+/// others instead of locking the device out. Fresh ones are generated the
+/// first time.
+pub fn unlock(data_dir: &Path, passphrase: &str, imported: Vec<KeyParams>) -> Result<KeyRing> {
+    let path = data_dir.join(KEY_PARAMS_FILE);
+    let mut candidates: Vec<KeyParams> = KeyParams::load(&path)?.into_iter().chain(imported).collect();
+    if candidates.is_empty() {
+        let (params, key) = KeyParams::generate(passphrase, KdfParams::default())?;
+        params.save(&path)?;
+        return Ok(key.into());
+    }
+    candidates.sort_by_key(|params| std::cmp::Reverse(params.epoch));
+    candidates.dedup();
+
+    let mut first_error = None;
+    for params in candidates {
+        match params.unlock(passphrase) {
+            Ok(ring) => {
+                params.save(&path)?;
+                return Ok(ring);
+            }
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    Err(first_error.expect("at least one candidate was tried"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unlock, KdfParams, KeyParams, KEY_PARAMS_FILE};
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let cheap = KdfParams {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let (params, key) = KeyParams::generate("correct horse", cheap).unwrap();
+
+        assert_eq!(params.derive("correct horse").unwrap().as_bytes(), key.as_bytes());
+        assert!(params.derive("wrong horse").is_err());
+
+        let (other, other_key) = KeyParams::generate("correct horse", cheap).unwrap();
+        assert_ne!(other.salt, params.salt);
+        assert_ne!(other_key.as_bytes(), key.as_bytes());
+    }
+
+    #[test]
+    fn rotation_keeps_earlier_keys_behind_the_new_passphrase() {
+        let cheap = KdfParams {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let (params, key) = KeyParams::generate("old", cheap).unwrap();
+        let ring = params.unlock("old").unwrap();
+
+        let (rotated, new_ring) = KeyParams::rotate(&ring, "new", cheap).unwrap();
+        assert_eq!(rotated.epoch, 1);
+        assert!(rotated.unlock("old").is_err());
+
+        let unlocked = rotated.unlock("new").unwrap();
+        assert_eq!(unlocked.current().0, 1);
+        assert_eq!(unlocked.current().1.as_bytes(), new_ring.current().1.as_bytes());
+        let old = unlocked.keys().find(|(epoch, _)| *epoch == 0).unwrap().1;
+        assert_eq!(old.as_bytes(), key.as_bytes());
+    }
+
+    #[test]
+    fn forged_params_fall_back_to_the_saved_ones() {
+        let cheap = KdfParams {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let dir = std::env::temp_dir().join(format!("oversync-keys-{}", uuid::Uuid::new_v4()));
+        let (saved, key) = KeyParams::generate("correct horse", cheap).unwrap();
+        saved.save(&dir.join(KEY_PARAMS_FILE)).unwrap();
+
+        // A later epoch whose check value can't match.
+        let mut forged = saved.clone();
+        forged.epoch = 7;
+        forged.check = [0; 32];
+
+        let ring = unlock(&dir, "correct horse", vec![forged]).unwrap();
+        assert_eq!(ring.current().0, 0);
+        assert_eq!(ring.current().1.as_bytes(), key.as_bytes());
+        assert_eq!(KeyParams::load(&dir.join(KEY_PARAMS_FILE)).unwrap(), Some(saved));
+        assert!(unlock(&dir, "wrong horse", Vec::new()).is_err());
+    }
+}
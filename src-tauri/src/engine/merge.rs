@@ -0,0 +1,228 @@
+/// Three-way merge of a Markdown note against its last common ancestor.
+///
+/// The body is merged line by line; YAML frontmatter is merged per top-level
+/// key, with list values (tags, aliases, ...) merged as sets so two devices
+/// adding different tags both win. Returns `None` when edits overlap.
+pub fn merge_markdown(base: &str, ours: &str, theirs: &str) -> Option<String> {
+    let (base_fm, base_body) = split_frontmatter(base);
+    let (our_fm, our_body) = split_frontmatter(ours);
+    let (their_fm, their_body) = split_frontmatter(theirs);
+
+    let body = diffy::merge(base_body, our_body, their_body).ok()?;
+
+    match (our_fm, their_fm) {
+        (None, None) if base_fm.is_none() => Some(body),
+        _ => {
+            let frontmatter = merge_frontmatter(
+                base_fm.unwrap_or(""),
+                our_fm.unwrap_or(""),
+                their_fm.unwrap_or(""),
+            )?;
+            if frontmatter.is_empty() {
+                Some(body)
+            } else {
+                Some(format!("---\n{}---\n{}", frontmatter, body))
+            }
+        }
+    }
+}
+
+/// Split `---`-delimited frontmatter (without the fences) from the body.
+fn split_frontmatter(text: &str) -> (Option<&str>, &str) {
+    let Some(rest) = text.strip_prefix("---\n").or_else(|| text.strip_prefix("---\r\n")) else {
+        return (None, text);
+    };
+
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == "---" {
+            return (Some(&rest[..offset]), &rest[offset + line.len()..]);
+        }
+        offset += line.len();
+    }
+
+    (None, text)
+}
+
+/// Top-level frontmatter entries as (key, raw lines), in file order. Lines
+/// that don't start a key (comments, continuations) stay with the entry above.
+fn parse_entries(frontmatter: &str) -> Vec<(String, String)> {
+    let mut entries: Vec<(String, String)> = Vec::new();
+
+    for line in frontmatter.split_inclusive('\n') {
+        let starts_key = !line.starts_with([' ', '\t', '-', '#']) && line.contains(':');
+        match entries.last_mut() {
+            Some((_, raw)) if !starts_key => raw.push_str(line),
+            _ => {
+                let key = if starts_key {
+                    line.split(':').next().unwrap_or_default().trim().to_string()
+                } else {
+                    line.to_string()
+                };
+                entries.push((key, line.to_string()));
+            }
+        }
+    }
+
+    entries
+}
+
+fn merge_frontmatter(base: &str, ours: &str, theirs: &str) -> Option<String> {
+    let base = parse_entries(base);
+    let ours = parse_entries(ours);
+    let theirs = parse_entries(theirs);
+
+    let lookup = |entries: &[(String, String)], key: &str| {
+        entries.iter().find(|(k, _)| k == key).map(|(_, raw)| raw.clone())
+    };
+
+    let mut keys: Vec<&String> = ours.iter().map(|(key, _)| key).collect();
+    for (key, _) in &theirs {
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+
+    let mut merged = String::new();
+    for key in keys {
+        let b = lookup(&base, key);
+        let o = lookup(&ours, key);
+        let t = lookup(&theirs, key);
+
+        let value = if o == t || t == b {
+            o
+        } else if o == b {
+            t
+        } else {
+            Some(merge_list_entry(key, b.as_deref(), o.as_deref()?, t.as_deref()?)?)
+        };
+
+        if let Some(raw) = value {
+            merged.push_str(&raw);
+            if !raw.ends_with('\n') {
+                merged.push('\n');
+            }
+        }
+    }
+
+    Some(merged)
+}
+
+enum ListStyle {
+    /// `tags: [a, b]`
+    Flow,
+    /// `tags:` followed by `  - a` lines, with the item indent.
+    Block(String),
+}
+
+fn parse_list(raw: &str) -> Option<(Vec<String>, ListStyle)> {
+    let (_, value) = raw.split_once(':')?;
+
+    if let Some(inner) = value.trim().strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+        let items = inner
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect();
+        return Some((items, ListStyle::Flow));
+    }
+
+    let mut lines = value.lines();
+    let mut items = Vec::new();
+    let mut indent = String::from("  ");
+    if lines.next().is_some_and(|first| !first.trim().is_empty()) {
+        return None;
+    }
+    for line in lines {
+        let trimmed = line.trim_start();
+        let item = trimmed.strip_prefix("- ")?;
+        indent = line[..line.len() - trimmed.len()].to_string();
+        items.push(item.trim().to_string());
+    }
+    Some((items, ListStyle::Block(indent)))
+}
+
+/// Set-wise three-way merge of a list value, keeping our item order and style.
+fn merge_list_entry(key: &str, base: Option<&str>, ours: &str, theirs: &str) -> Option<String> {
+    let base_items = match base {
+        Some(raw) => parse_list(raw)?.0,
+        None => Vec::new(),
+    };
+    let (our_items, style) = parse_list(ours)?;
+    let (their_items, _) = parse_list(theirs)?;
+
+    let removed_by_them = |item: &String| base_items.contains(item) && !their_items.contains(item);
+    let mut items: Vec<String> = our_items.iter().filter(|item| !removed_by_them(item)).cloned().collect();
+    for item in &their_items {
+        if !base_items.contains(item) && !items.contains(item) {
+            items.push(item.clone());
+        }
+    }
+
+    Some(match style {
+        ListStyle::Flow => format!("{}: [{}]\n", key, items.join(", ")),
+        ListStyle::Block(indent) => {
+            let mut raw = format!("{}:\n", key);
+            for item in items {
+                raw.push_str(&format!("{}- {}\n", indent, item));
+            }
+            raw
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::merge_markdown;
+
+    #[test]
+    fn merges_edits_to_different_paragraphs() {
+        let base = "# Title\n\nfirst\n\nsecond\n";
+        let ours = "# Title\n\nfirst, edited here\n\nsecond\n";
+        let theirs = "# Title\n\nfirst\n\nsecond, edited there\n";
+
+        assert_eq!(
+            merge_markdown(base, ours, theirs).unwrap(),
+            "# Title\n\nfirst, edited here\n\nsecond, edited there\n"
+        );
+    }
+
+    #[test]
+    fn overlapping_edits_do_not_merge() {
+        let base = "line\n";
+        assert!(merge_markdown(base, "ours\n", "theirs\n").is_none());
+    }
+
+    #[test]
+    fn frontmatter_tags_merge_as_sets() {
+        let base = "---\ntitle: Note\ntags: [a, b]\n---\nbody\n";
+        let ours = "---\ntitle: Note\ntags: [a, b, laptop]\n---\nbody\n";
+        let theirs = "---\ntitle: Renamed\ntags: [b, phone]\n---\nbody\n";
+
+        assert_eq!(
+            merge_markdown(base, ours, theirs).unwrap(),
+            "---\ntitle: Renamed\ntags: [b, laptop, phone]\n---\nbody\n"
+        );
+    }
+
+    #[test]
+    fn frontmatter_block_lists_merge() {
+        let base = "---\ntags:\n  - a\n---\n";
+        let ours = "---\ntags:\n  - a\n  - x\n---\n";
+        let theirs = "---\ntags:\n  - a\n  - y\naliases: [n]\n---\n";
+
+        assert_eq!(
+            merge_markdown(base, ours, theirs).unwrap(),
+            "---\ntags:\n  - a\n  - x\n  - y\naliases: [n]\n---\n"
+        );
+    }
+
+    #[test]
+    fn conflicting_scalar_frontmatter_does_not_merge() {
+        let base = "---\nstatus: draft\n---\n";
+        let ours = "---\nstatus: done\n---\n";
+        let theirs = "---\nstatus: review\n---\n";
+
+        assert!(merge_markdown(base, ours, theirs).is_none());
+    }
+}
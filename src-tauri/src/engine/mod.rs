@@ -1,10 +1,21 @@
 pub mod p2p;
+pub mod protocol;
 pub mod watcher;
 pub mod github;
+pub mod remote;
 pub mod encryption;
+pub mod keys;
 pub mod storage;
+pub mod index_store;
 pub mod sync;
 pub mod neon;
+pub mod version;
+pub mod conflict;
+pub mod merge;
+pub mod queue;
+pub mod chunker;
+pub mod ignore_rules;
+pub mod obsidian_config;
 
 use serde::{Serialize, Deserialize};
 
@@ -16,6 +27,28 @@ pub struct SyncStatus {
     pub is_syncing: bool,
     pub last_sync: Option<chrono::DateTime<chrono::Utc>>,
     pub peers_connected: usize,
+    /// Set while the startup scan is comparing the vault against the index.
+    pub scan: Option<ScanProgress>,
+    /// Set while files are being re-encrypted after a key rotation.
+    #[serde(default)]
+    pub rotation: Option<RotationProgress>,
+    /// Remotes whose requests are held back by a rate limit, by name: when
+    /// they resume.
+    #[serde(default)]
+    pub remote_limits: std::collections::BTreeMap<String, chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScanProgress {
+    pub scanned: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RotationProgress {
+    pub epoch: u32,
+    pub done: usize,
+    pub total: usize,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -24,4 +57,11 @@ pub struct GithubConfig {
     pub owner: String,
     pub repo: String,
     pub branch: String,
+    /// REST endpoint of a GitHub Enterprise server; github.com if unset.
+    #[serde(default)]
+    pub api_url: Option<String>,
+    /// Files larger than this many bytes are stored as release assets
+    /// instead of in the repository; 16 MiB if unset.
+    #[serde(default)]
+    pub large_file_threshold: Option<u64>,
 }
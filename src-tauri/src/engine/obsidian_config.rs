@@ -0,0 +1,137 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+/// Where a device's category selection is kept, in the app data directory.
+pub const CONFIG_SYNC_FILE: &str = "config_sync.json";
+
+/// Groups of files under `.obsidian/` that can be synced independently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigCategory {
+    /// `app.json`, core plugin switches and their settings.
+    CoreSettings,
+    Appearance,
+    Themes,
+    Snippets,
+    /// Plugin code and the list of enabled plugins.
+    CommunityPlugins,
+    /// Each plugin's `data.json`.
+    PluginData,
+    Hotkeys,
+    /// Open panes and layout, usually specific to one device.
+    Workspace,
+}
+
+impl ConfigCategory {
+    /// In rule order: later categories carve their files out of earlier,
+    /// broader ones (core settings match every top-level `.json`).
+    pub const ALL: [ConfigCategory; 8] = [
+        ConfigCategory::CoreSettings,
+        ConfigCategory::Appearance,
+        ConfigCategory::Themes,
+        ConfigCategory::Snippets,
+        ConfigCategory::CommunityPlugins,
+        ConfigCategory::PluginData,
+        ConfigCategory::Hotkeys,
+        ConfigCategory::Workspace,
+    ];
+
+    /// gitignore patterns for the category's files. Folders are matched by
+    /// their contents so a later category can still re-include a file.
+    fn patterns(self) -> &'static [&'static str] {
+        match self {
+            ConfigCategory::CoreSettings => &[".obsidian/*.json"],
+            ConfigCategory::Appearance => &[".obsidian/appearance.json"],
+            ConfigCategory::Themes => &[".obsidian/themes/**"],
+            ConfigCategory::Snippets => &[".obsidian/snippets/**"],
+            ConfigCategory::CommunityPlugins => &[
+                ".obsidian/community-plugins.json",
+                ".obsidian/plugins/*/**",
+            ],
+            ConfigCategory::PluginData => &[".obsidian/plugins/*/data.json"],
+            ConfigCategory::Hotkeys => &[".obsidian/hotkeys.json"],
+            ConfigCategory::Workspace => &[
+                ".obsidian/workspace.json",
+                ".obsidian/workspace-mobile.json",
+                ".obsidian/workspaces.json",
+            ],
+        }
+    }
+}
+
+/// Which `.obsidian/` categories this device syncs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfigSyncSettings {
+    pub enabled: BTreeSet<ConfigCategory>,
+}
+
+impl Default for ConfigSyncSettings {
+    /// Everything but the workspace layout.
+    fn default() -> Self {
+        Self {
+            enabled: ConfigCategory::ALL
+                .into_iter()
+                .filter(|category| *category != ConfigCategory::Workspace)
+                .collect(),
+        }
+    }
+}
+
+impl ConfigSyncSettings {
+    /// Missing file means defaults.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read(path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Ignore rules excluding disabled categories and re-including enabled
+    /// ones, to be layered between the built-in defaults and `.oversyncignore`.
+    pub fn ignore_rules(&self) -> Vec<String> {
+        let mut rules = Vec::new();
+        for category in ConfigCategory::ALL {
+            let negate = if self.enabled.contains(&category) { "!" } else { "" };
+            for pattern in category.patterns() {
+                rules.push(format!("{}{}", negate, pattern));
+            }
+        }
+        rules
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConfigCategory, ConfigSyncSettings};
+    use crate::engine::ignore_rules::IgnoreRules;
+    use std::path::Path;
+
+    #[test]
+    fn categories_select_obsidian_files() {
+        let mut settings = ConfigSyncSettings::default();
+        settings.enabled.remove(&ConfigCategory::CoreSettings);
+        settings.enabled.remove(&ConfigCategory::PluginData);
+        let rules = IgnoreRules::from_rules(Path::new(""), &settings.ignore_rules()).unwrap();
+        let ignored = |path: &str| rules.is_ignored(Path::new(path), false);
+
+        assert!(ignored(".obsidian/app.json"));
+        assert!(ignored(".obsidian/workspace.json"));
+        assert!(ignored(".obsidian/plugins/dataview/data.json"));
+        assert!(!ignored(".obsidian/hotkeys.json"));
+        assert!(!ignored(".obsidian/appearance.json"));
+        assert!(!ignored(".obsidian/plugins/dataview/main.js"));
+        assert!(!ignored(".obsidian/themes/Minimal/theme.css"));
+        assert!(!rules.is_ignored(Path::new(".obsidian/plugins/dataview"), true));
+    }
+}
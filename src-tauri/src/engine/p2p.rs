@@ -1,16 +1,23 @@
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Instant;
 use anyhow::Result;
 use iroh::node::Node;
 use iroh::net::key::SecretKey;
 use iroh::base::ticket::NodeTicket;
 use iroh::net::NodeId;
 use iroh::blobs::store::fs::Store;
-use iroh::blobs::protocol::ALPN;
+use iroh::blobs::store::{ReadableStore as _, Store as _};
+use iroh::blobs::hashseq::HashSeq;
+use iroh::blobs::BlobFormat;
 use serde::{Serialize, Deserialize};
-use tokio::sync::{broadcast, Mutex};
+use tokio::sync::{broadcast, Mutex, RwLock};
 use tokio::fs;
 use tokio_stream::StreamExt;
+use crate::engine::ignore_rules::IgnoreRules;
+use crate::engine::protocol::{pair, pull_changes, PairingCodes, SyncProtocol, PAIRING_WINDOW, SYNC_ALPN};
+use crate::engine::storage::{move_source, FileMetadata, VaultIndexer};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum P2pEvent {
@@ -19,17 +26,42 @@ pub enum P2pEvent {
     SyncStarted(String),
     SyncFinished(String),
     SyncFailed { peer: String, error: String },
+    /// Entries that differ from the local index, with their blobs downloaded.
+    RemoteChanges { peer: String, changes: Vec<FileMetadata> },
 }
 
+/// Separates a ticket's node address from the pairing code that follows it.
+pub const PAIRING_SEPARATOR: char = '#';
+
 pub struct P2pNode {
     node: Node<Store>,
+    store: Store,
     _secret_key: SecretKey,
     event_tx: broadcast::Sender<P2pEvent>,
     active_peers: Arc<Mutex<Vec<NodeId>>>,
+    pairing_codes: PairingCodes,
+    indexer: Arc<RwLock<VaultIndexer>>,
+    ignore: Arc<RwLock<IgnoreRules>>,
+}
+
+pub(crate) async fn register_peer(
+    active_peers: &Mutex<Vec<NodeId>>,
+    event_tx: &broadcast::Sender<P2pEvent>,
+    peer_id: NodeId,
+) {
+    let mut peers = active_peers.lock().await;
+    if !peers.contains(&peer_id) {
+        peers.push(peer_id);
+        let _ = event_tx.send(P2pEvent::PeerConnected(peer_id.to_string()));
+    }
 }
 
 impl P2pNode {
-    pub async fn new(data_dir: PathBuf) -> Result<Self> {
+    pub async fn new(
+        data_dir: PathBuf,
+        indexer: Arc<RwLock<VaultIndexer>>,
+        ignore: Arc<RwLock<IgnoreRules>>,
+    ) -> Result<Self> {
         if !data_dir.exists() {
             fs::create_dir_all(&data_dir).await?;
         }
@@ -44,80 +76,208 @@ impl P2pNode {
             sk
         };
 
+        let (event_tx, _) = broadcast::channel(100);
+        let active_peers = Arc::new(Mutex::new(Vec::new()));
+        let pairing_codes = PairingCodes::default();
+
+        let protocol = SyncProtocol::new(indexer.clone(), event_tx.clone(), active_peers.clone(), pairing_codes.clone());
         let node = Node::persistent(data_dir.join("iroh_data"))
             .await?
             .secret_key(secret_key.clone())
+            .build()
+            .await?;
+        let store = node.blobs_db().clone();
+        let node = node
+            .accept(SYNC_ALPN, Arc::new(protocol))
             .spawn()
             .await?;
 
-        let (event_tx, _) = broadcast::channel(100);
-        let active_peers = Arc::new(Mutex::new(Vec::new()));
-
         let node_clone = node.clone();
 
         // Spawn connection monitor
         tokio::spawn(async move {
             let mut endpoint_events = node_clone.endpoint().watch_home_relay();
-            while let Some(_) = endpoint_events.next().await {
+            while endpoint_events.next().await.is_some() {
                 // Simplified connection monitoring
             }
         });
 
         Ok(Self {
             node,
+            store,
             _secret_key: secret_key,
             event_tx,
             active_peers,
+            pairing_codes,
+            indexer,
+            ignore,
         })
     }
 
+    /// Our address, followed by a pairing code the device it is given to
+    /// can pair with once, within `PAIRING_WINDOW`.
     pub async fn ticket(&self) -> Result<String> {
         let addr = self.node.endpoint().node_addr().await?;
         let ticket = NodeTicket::new(addr)?;
-        Ok(ticket.to_string())
+        let code = uuid::Uuid::new_v4().simple().to_string();
+        let mut codes = self.pairing_codes.lock().await;
+        codes.retain(|_, issued| issued.elapsed() < PAIRING_WINDOW);
+        codes.insert(code.clone(), Instant::now());
+        Ok(format!("{}{}{}", ticket, PAIRING_SEPARATOR, code))
     }
 
     pub async fn connect(&self, ticket_str: &str) -> Result<()> {
+        let (ticket_str, code) = ticket_str
+            .trim()
+            .rsplit_once(PAIRING_SEPARATOR)
+            .ok_or_else(|| anyhow::anyhow!("Ticket has no pairing code; generate a new one"))?;
         let ticket = ticket_str.parse::<NodeTicket>()
             .map_err(|_| anyhow::anyhow!("Invalid ticket format"))?;
-        
-        self.node.endpoint().connect(ticket.node_addr().clone(), ALPN).await?;
-        
+
+        self.node.endpoint().add_node_addr(ticket.node_addr().clone())?;
+
         let peer_id = ticket.node_addr().node_id;
-        let mut peers = self.active_peers.lock().await;
-        if !peers.contains(&peer_id) {
-            peers.push(peer_id);
-            let _ = self.event_tx.send(P2pEvent::PeerConnected(peer_id.to_string()));
-        }
+        pair(self.node.endpoint(), peer_id, code).await?;
+        self.indexer.write().await.add_device(&peer_id.to_string())?;
+        register_peer(&self.active_peers, &self.event_tx, peer_id).await;
 
+        self.sync_with(peer_id).await?;
         Ok(())
     }
 
-    pub async fn sync_blob(&self, peer_id: NodeId, hash: iroh::blobs::Hash) -> Result<()> {
+    /// Run an anti-entropy round against `peer_id`, downloading the blobs for
+    /// every entry that differs and announcing them as `RemoteChanges`.
+    pub async fn sync_with(&self, peer_id: NodeId) -> Result<Vec<FileMetadata>> {
         let _ = self.event_tx.send(P2pEvent::SyncStarted(peer_id.to_string()));
-        
-        let client = self.node.blobs();
-        let download = client.download(hash, peer_id.into()).await?;
-        
-        match download.await {
-            Ok(_) => {
+
+        match self.pull_from(peer_id).await {
+            Ok(changes) => {
+                if !changes.is_empty() {
+                    let _ = self.event_tx.send(P2pEvent::RemoteChanges {
+                        peer: peer_id.to_string(),
+                        changes: changes.clone(),
+                    });
+                }
                 let _ = self.event_tx.send(P2pEvent::SyncFinished(peer_id.to_string()));
-                Ok(())
+                Ok(changes)
             }
             Err(e) => {
                 let _ = self.event_tx.send(P2pEvent::SyncFailed {
                     peer: peer_id.to_string(),
                     error: e.to_string(),
                 });
-                Err(e.into())
+                Err(e)
+            }
+        }
+    }
+
+    async fn pull_from(&self, peer_id: NodeId) -> Result<Vec<FileMetadata>> {
+        let mut changes = pull_changes(self.node.endpoint(), peer_id, &self.indexer).await?;
+
+        // Entries the peer hasn't stored a blob for yet are left for the next
+        // round. Tombstones have no content to fetch.
+        changes.retain(|meta| meta.blob.is_some() || meta.is_deleted());
+        let ignore = self.ignore.read().await;
+        changes.retain(|meta| !ignore.is_ignored(Path::new(&meta.path), false));
+        drop(ignore);
+        let indexer = self.indexer.read().await;
+        let renames: Vec<bool> = changes
+            .iter()
+            .map(|meta| move_source(meta, &changes).is_some_and(|source| indexer.can_rename(meta, source)))
+            .collect();
+        drop(indexer);
+
+        for (meta, renamed) in changes.iter().zip(renames) {
+            // Moves of files we already have are replayed as local renames.
+            if renamed {
+                continue;
+            }
+            if let Some(blob) = meta.blob {
+                self.sync_content(peer_id, blob.into()).await?;
+            }
+        }
+
+        Ok(changes)
+    }
+
+    /// Run an anti-entropy round against every paired device, dialed by node
+    /// id, so pairings made before a restart keep syncing.
+    pub async fn sync_all(&self) {
+        let peers: Vec<NodeId> = self
+            .indexer
+            .read()
+            .await
+            .known_devices()
+            .filter_map(|device| device.parse().ok())
+            .collect();
+        for peer_id in peers {
+            if let Err(e) = self.sync_with(peer_id).await {
+                eprintln!("Anti-entropy round with {} failed: {}", peer_id, e);
             }
         }
     }
 
+    /// Fetch a file's hash sequence and whichever of its chunks aren't
+    /// stored locally yet.
+    pub async fn sync_content(&self, peer_id: NodeId, hash: iroh::blobs::Hash) -> Result<()> {
+        let download = self.node.blobs().download_hash_seq(hash, peer_id.into()).await?;
+        download.await?;
+        Ok(())
+    }
+
     pub async fn add_blob(&self, data: Vec<u8>) -> Result<iroh::blobs::Hash> {
-        let client = self.node.blobs();
-        let hash = client.add_bytes(data).await?;
-        Ok(hash.hash)
+        // Imported straight into the store: the RPC `add_bytes` path is a
+        // bidi stream whose server side can panic when the client finishes.
+        let temp_tag = self.store.import_bytes(data.into(), BlobFormat::Raw).await?;
+        self.store.create_tag(temp_tag.hash_and_format()).await?;
+        Ok(*temp_tag.hash())
+    }
+
+    /// Store the ordered list of a file's chunk blobs as a hash sequence,
+    /// which keeps the chunks alive and lets peers fetch them in one request.
+    pub async fn add_hash_seq(&self, blobs: &[iroh::blobs::Hash]) -> Result<iroh::blobs::Hash> {
+        let seq: HashSeq = blobs.iter().copied().collect();
+        let temp_tag = self.store.import_bytes(seq.into_inner(), BlobFormat::HashSeq).await?;
+        self.store.create_tag(temp_tag.hash_and_format()).await?;
+        Ok(*temp_tag.hash())
+    }
+
+    /// Untag and delete `blobs` from the local store, such as chunks sealed
+    /// under a key that has since been rotated out.
+    pub async fn remove_blobs(&self, blobs: &HashSet<iroh::blobs::Hash>) -> Result<()> {
+        if blobs.is_empty() {
+            return Ok(());
+        }
+        let tags: Vec<_> = self.store.tags().await?.collect::<std::io::Result<_>>()?;
+        for (tag, target) in tags {
+            if blobs.contains(&target.hash) {
+                self.store.set_tag(tag, None).await?;
+            }
+        }
+        // Blobs imported or opened since the last collection are protected
+        // from deletion until the next one starts.
+        self.store.gc_start().await?;
+        self.store.delete(blobs.iter().copied().collect()).await?;
+        Ok(())
+    }
+
+    pub async fn read_hash_seq(&self, hash: iroh::blobs::Hash) -> Result<Vec<iroh::blobs::Hash>> {
+        let bytes = self.node.blobs().read_to_bytes(hash).await?;
+        Ok(HashSeq::try_from(bytes)?.into_iter().collect())
+    }
+
+    pub async fn has_blob(&self, hash: iroh::blobs::Hash) -> Result<bool> {
+        self.node.blobs().has(hash).await
+    }
+
+    /// Stream a blob from the local store.
+    pub async fn blob_reader(&self, hash: iroh::blobs::Hash) -> Result<iroh::client::blobs::Reader> {
+        self.node.blobs().read(hash).await
+    }
+
+    pub async fn read_blob(&self, hash: iroh::blobs::Hash) -> Result<Vec<u8>> {
+        let bytes = self.node.blobs().read_to_bytes(hash).await?;
+        Ok(bytes.to_vec())
     }
 
     pub fn subscribe(&self) -> broadcast::Receiver<P2pEvent> {
@@ -127,4 +287,111 @@ impl P2pNode {
     pub async fn node_id(&self) -> NodeId {
         self.node.node_id()
     }
+
+    /// Stop the node, saving the addresses of the peers it knows so they
+    /// can be dialed by node id on the next start.
+    pub async fn shutdown(self) -> Result<()> {
+        self.node.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{P2pEvent, P2pNode, PAIRING_SEPARATOR};
+    use crate::engine::storage::{Chunk, VaultIndexer};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("oversync-{}-{}", name, uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn anti_entropy_pulls_divergent_entries() {
+        let index_a = Arc::new(RwLock::new(VaultIndexer::new()));
+        let index_b = Arc::new(RwLock::new(VaultIndexer::new()));
+        let node_a = P2pNode::new(temp_dir("a"), index_a.clone(), Default::default()).await.unwrap();
+        let node_b = P2pNode::new(temp_dir("b"), index_b.clone(), Default::default()).await.unwrap();
+
+        let chunk = node_a.add_blob(b"sealed note".to_vec()).await.unwrap();
+        let blob = node_a.add_hash_seq(&[chunk]).await.unwrap();
+        {
+            let mut a = index_a.write().await;
+            a.update_file("shared.md".to_string(), b"same", 0).unwrap();
+            a.update_file("note.md".to_string(), b"hello", 1).unwrap();
+            let chunks = [Chunk { hash: blake3::hash(b"hello").into(), blob: chunk.into(), size: 5, epoch: 0 }];
+            a.set_blob("note.md", blake3::hash(b"hello").into(), blob.into(), &chunks).unwrap();
+        }
+        index_b
+            .write()
+            .await
+            .update_file("shared.md".to_string(), b"same", 0)
+            .unwrap();
+
+        let mut events = node_b.subscribe();
+        node_b.connect(&node_a.ticket().await.unwrap()).await.unwrap();
+
+        let changes = loop {
+            if let P2pEvent::RemoteChanges { changes, .. } = events.recv().await.unwrap() {
+                break changes;
+            }
+        };
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].path, "note.md");
+        assert_eq!(node_b.read_hash_seq(blob).await.unwrap(), vec![chunk]);
+        assert!(node_b.has_blob(chunk).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn restarted_node_keeps_pulling_from_its_paired_peer() {
+        let index_a = Arc::new(RwLock::new(VaultIndexer::new()));
+        let node_a = P2pNode::new(temp_dir("a"), index_a.clone(), Default::default()).await.unwrap();
+        let dir_b = temp_dir("b");
+        let node_b = P2pNode::new(dir_b.clone(), Default::default(), Default::default()).await.unwrap();
+        node_b.connect(&node_a.ticket().await.unwrap()).await.unwrap();
+        let paired = node_b.indexer.read().await.known_devices().map(str::to_string).collect::<Vec<_>>();
+        node_b.shutdown().await.unwrap();
+
+        // A deletion, which has no blob to fetch.
+        index_a.write().await.update_file("note.md".to_string(), b"hello", 0).unwrap();
+        index_a.write().await.remove_file("note.md", 1).unwrap();
+
+        // The pairing outlives the node; the index would be reopened from disk.
+        let index_b = Arc::new(RwLock::new(VaultIndexer::new()));
+        for device in &paired {
+            index_b.write().await.add_device(device).unwrap();
+        }
+        let node_b = P2pNode::new(dir_b, index_b, Default::default()).await.unwrap();
+        let mut events = node_b.subscribe();
+        node_b.sync_all().await;
+
+        let changes = loop {
+            if let P2pEvent::RemoteChanges { changes, .. } = events.recv().await.unwrap() {
+                break changes;
+            }
+        };
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].path, "note.md");
+    }
+
+    #[tokio::test]
+    async fn only_paired_devices_are_answered() {
+        let index_a = Arc::new(RwLock::new(VaultIndexer::new()));
+        let node_a = P2pNode::new(temp_dir("a"), index_a.clone(), Default::default()).await.unwrap();
+        let node_b = P2pNode::new(temp_dir("b"), Default::default(), Default::default()).await.unwrap();
+        let node_c = P2pNode::new(temp_dir("c"), Default::default(), Default::default()).await.unwrap();
+        index_a.write().await.update_file("note.md".to_string(), b"hello", 0).unwrap();
+
+        let ticket = node_a.ticket().await.unwrap();
+        let (address, _) = ticket.rsplit_once(PAIRING_SEPARATOR).unwrap();
+        assert!(node_b.connect(address).await.is_err());
+        assert!(node_b.connect(&format!("{}{}guess", address, PAIRING_SEPARATOR)).await.is_err());
+        assert!(node_b.sync_with(node_a.node_id().await).await.is_err());
+
+        node_b.connect(&ticket).await.unwrap();
+        assert!(index_a.read().await.is_known_device(&node_b.node_id().await.to_string()));
+        // Each code pairs one device.
+        assert!(node_c.connect(&ticket).await.is_err());
+    }
 }
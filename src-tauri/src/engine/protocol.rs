@@ -0,0 +1,199 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use iroh::net::endpoint::{get_remote_node_id, Connecting, Connection};
+use iroh::net::{Endpoint, NodeId};
+use iroh::node::ProtocolHandler;
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use tokio::sync::{broadcast, Mutex, RwLock};
+use crate::engine::p2p::{register_peer, P2pEvent};
+use crate::engine::storage::{FileMetadata, KeyRange, PageRangeDigest, VaultIndexer};
+
+/// ALPN for the vault anti-entropy protocol.
+pub const SYNC_ALPN: &[u8] = b"oversync/1";
+
+const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// How long the pairing code in a ticket can be used for.
+pub const PAIRING_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Pairing codes handed out in tickets and not used yet, by when.
+pub type PairingCodes = Arc<Mutex<HashMap<String, Instant>>>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SyncRequest {
+    /// Pair with the code from one of the responder's tickets. Sent first
+    /// by a device the responder doesn't know yet.
+    Pair(String),
+    RootHash,
+    Pages(Vec<PageRangeDigest>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SyncResponse {
+    Paired,
+    RootHash([u8; 32]),
+    Entries {
+        consistent: Vec<KeyRange>,
+        entries: Vec<FileMetadata>,
+    },
+}
+
+/// Answers `oversync/1` requests from the local vault index, for paired
+/// devices only.
+pub struct SyncProtocol {
+    indexer: Arc<RwLock<VaultIndexer>>,
+    event_tx: broadcast::Sender<P2pEvent>,
+    active_peers: Arc<Mutex<Vec<NodeId>>>,
+    pairing_codes: PairingCodes,
+}
+
+impl fmt::Debug for SyncProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyncProtocol").finish_non_exhaustive()
+    }
+}
+
+impl SyncProtocol {
+    pub fn new(
+        indexer: Arc<RwLock<VaultIndexer>>,
+        event_tx: broadcast::Sender<P2pEvent>,
+        active_peers: Arc<Mutex<Vec<NodeId>>>,
+        pairing_codes: PairingCodes,
+    ) -> Self {
+        Self { indexer, event_tx, active_peers, pairing_codes }
+    }
+
+    async fn respond(&self, request: SyncRequest) -> SyncResponse {
+        let mut indexer = self.indexer.write().await;
+        match request {
+            SyncRequest::Pair(_) => SyncResponse::Paired,
+            SyncRequest::RootHash => SyncResponse::RootHash(indexer.root_hash()),
+            SyncRequest::Pages(pages) => {
+                let consistent = indexer.consistent_ranges(&pages);
+                let entries = indexer
+                    .entries_outside(&consistent)
+                    .keys()
+                    .filter_map(|path| indexer.get_metadata(path).cloned())
+                    .collect();
+                SyncResponse::Entries { consistent, entries }
+            }
+        }
+    }
+
+    async fn serve(&self, connection: Connection) -> Result<()> {
+        let peer_id = get_remote_node_id(&connection)?;
+        if !self.indexer.read().await.is_known_device(&peer_id.to_string()) {
+            if let Err(e) = self.pair(&connection, peer_id).await {
+                connection.close(1u32.into(), b"not paired");
+                return Err(e);
+            }
+        }
+        register_peer(&self.active_peers, &self.event_tx, peer_id).await;
+
+        // The requester closes the connection once it has what it needs.
+        while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+            let request: SyncRequest = serde_json::from_slice(&recv.read_to_end(MAX_MESSAGE_SIZE).await?)?;
+            let response = self.respond(request).await;
+            send.write_all(&serde_json::to_vec(&response)?).await?;
+            send.finish()?;
+        }
+
+        Ok(())
+    }
+
+    /// Pair with `peer_id` if its first request carries a pairing code we
+    /// handed out, using the code up.
+    async fn pair(&self, connection: &Connection, peer_id: NodeId) -> Result<()> {
+        let (mut send, mut recv) = connection.accept_bi().await?;
+        let SyncRequest::Pair(code) = serde_json::from_slice(&recv.read_to_end(MAX_MESSAGE_SIZE).await?)? else {
+            return Err(anyhow!("refusing {}: not paired", peer_id));
+        };
+        let issued = self.pairing_codes.lock().await.remove(&code);
+        if issued.is_none_or(|issued| issued.elapsed() >= PAIRING_WINDOW) {
+            return Err(anyhow!("refusing {}: unknown or expired pairing code", peer_id));
+        }
+        self.indexer.write().await.add_device(&peer_id.to_string())?;
+        send.write_all(&serde_json::to_vec(&SyncResponse::Paired)?).await?;
+        send.finish()?;
+        Ok(())
+    }
+}
+
+impl ProtocolHandler for SyncProtocol {
+    fn accept(self: Arc<Self>, conn: Connecting) -> BoxFuture<'static, Result<()>> {
+        Box::pin(async move {
+            let connection = conn.await?;
+            self.serve(connection).await
+        })
+    }
+}
+
+/// Pair with `peer_id` using `code`, from its ticket, so it answers our
+/// anti-entropy rounds.
+pub async fn pair(endpoint: &Endpoint, peer_id: NodeId, code: &str) -> Result<()> {
+    let connection = endpoint.connect_by_node_id(peer_id, SYNC_ALPN).await?;
+    match request(&connection, &SyncRequest::Pair(code.to_string())).await? {
+        SyncResponse::Paired => {
+            connection.close(0u32.into(), b"paired");
+            Ok(())
+        }
+        other => Err(anyhow!("unexpected response to Pair: {:?}", other)),
+    }
+}
+
+/// Run one anti-entropy round against `peer_id` and return the peer's
+/// entries for every path whose content differs from the local index.
+///
+/// Paths only present locally are not returned; the peer picks those up when
+/// it runs its own round against us.
+pub async fn pull_changes(
+    endpoint: &Endpoint,
+    peer_id: NodeId,
+    indexer: &RwLock<VaultIndexer>,
+) -> Result<Vec<FileMetadata>> {
+    let connection = endpoint.connect_by_node_id(peer_id, SYNC_ALPN).await?;
+
+    let remote_root = match request(&connection, &SyncRequest::RootHash).await? {
+        SyncResponse::RootHash(root) => root,
+        other => return Err(anyhow!("unexpected response to RootHash: {:?}", other)),
+    };
+
+    let pages = {
+        let mut indexer = indexer.write().await;
+        if indexer.root_hash() == remote_root {
+            connection.close(0u32.into(), b"in sync");
+            return Ok(Vec::new());
+        }
+        indexer.page_ranges()
+    };
+
+    let (consistent, entries) = match request(&connection, &SyncRequest::Pages(pages)).await? {
+        SyncResponse::Entries { consistent, entries } => (consistent, entries),
+        other => return Err(anyhow!("unexpected response to Pages: {:?}", other)),
+    };
+    connection.close(0u32.into(), b"done");
+
+    let hashes: BTreeMap<String, [u8; 32]> = entries
+        .iter()
+        .map(|meta| (meta.path.clone(), meta.hash))
+        .collect();
+    let changed = indexer.read().await.compare_entries(&consistent, &hashes);
+
+    Ok(entries
+        .into_iter()
+        .filter(|meta| changed.binary_search(&meta.path).is_ok())
+        .collect())
+}
+
+async fn request<T: DeserializeOwned>(connection: &Connection, request: &SyncRequest) -> Result<T> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+    send.write_all(&serde_json::to_vec(request)?).await?;
+    send.finish()?;
+    let bytes = recv.read_to_end(MAX_MESSAGE_SIZE).await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
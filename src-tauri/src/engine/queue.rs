@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Default quiet period before a changed file is processed.
+pub const DEFAULT_QUIET_PERIOD: Duration = Duration::from_millis(500);
+
+/// Coalesces watcher events per path until the path has been quiet for a
+/// while, so a burst of autosaves is read, hashed and uploaded once.
+pub struct ChangeQueue {
+    quiet_period: Duration,
+    pending: HashMap<PathBuf, Instant>,
+}
+
+impl Default for ChangeQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_QUIET_PERIOD)
+    }
+}
+
+impl ChangeQueue {
+    pub fn new(quiet_period: Duration) -> Self {
+        Self {
+            quiet_period,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn quiet_period(&self) -> Duration {
+        self.quiet_period
+    }
+
+    pub fn set_quiet_period(&mut self, quiet_period: Duration) {
+        self.quiet_period = quiet_period;
+    }
+
+    /// Record a change to `path`, restarting its quiet period.
+    pub fn push(&mut self, path: PathBuf, now: Instant) {
+        self.pending.insert(path, now);
+    }
+
+    /// Drop a pending change, e.g. because the file was removed.
+    pub fn cancel(&mut self, path: &Path) {
+        self.pending.remove(path);
+    }
+
+    /// Paths that haven't changed for the quiet period, oldest first.
+    pub fn take_settled(&mut self, now: Instant) -> Vec<PathBuf> {
+        let mut settled: Vec<(PathBuf, Instant)> = self
+            .pending
+            .iter()
+            .filter(|(_, at)| now.duration_since(**at) >= self.quiet_period)
+            .map(|(path, at)| (path.clone(), *at))
+            .collect();
+        settled.sort_by_key(|(_, at)| *at);
+
+        for (path, _) in &settled {
+            self.pending.remove(path);
+        }
+        settled.into_iter().map(|(path, _)| path).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChangeQueue;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn bursts_coalesce_into_one_change() {
+        let mut queue = ChangeQueue::new(Duration::from_millis(100));
+        let start = Instant::now();
+        let note = PathBuf::from("note.md");
+
+        for i in 0..10 {
+            queue.push(note.clone(), start + Duration::from_millis(i * 20));
+        }
+        assert!(queue.take_settled(start + Duration::from_millis(200)).is_empty());
+        assert_eq!(queue.take_settled(start + Duration::from_millis(280)), vec![note]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn cancelled_changes_are_dropped() {
+        let mut queue = ChangeQueue::new(Duration::ZERO);
+        let now = Instant::now();
+
+        queue.push(PathBuf::from("a.md"), now);
+        queue.push(PathBuf::from("b.md"), now);
+        queue.cancel(&PathBuf::from("a.md"));
+
+        assert_eq!(queue.take_settled(now), vec![PathBuf::from("b.md")]);
+    }
+}
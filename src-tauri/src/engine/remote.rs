@@ -0,0 +1,375 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+use crate::engine::keys::KeyParams;
+use crate::engine::storage::{FileMetadata, Tombstone};
+use crate::engine::version::{Causality, VersionVector};
+
+/// Storage the vault is synced through besides peers, such as a GitHub
+/// repository: content objects, and one manifest listing every file that
+/// can be swapped atomically. Backends encrypt what they store.
+///
+/// Content that is put only takes effect once a manifest that refers to it
+/// is swapped in, and is deleted by the swap of a manifest that no longer
+/// does.
+pub trait RemoteBackend: Send + Sync {
+    /// Unique among the configured backends; names the peer changes from
+    /// it are applied as.
+    fn name(&self) -> &str;
+
+    /// When writes are held back by a rate limit until, if they are.
+    fn limited_until(&self) -> Option<DateTime<Utc>> {
+        None
+    }
+
+    /// The manifest's version, to swap it against, and the manifest.
+    fn list(&self) -> BoxFuture<'_, Result<(String, RemoteIndex)>>;
+
+    /// Write the content `entry` refers to into `writer`. Returns its size.
+    fn get<'a>(&'a self, entry: &'a RemoteEntry, writer: &'a mut (dyn AsyncWrite + Send + Unpin)) -> BoxFuture<'a, Result<u64>>;
+
+    /// Store what `reader` yields, `size` bytes. Returns an entry referring
+    /// to it, without a version.
+    fn put<'a>(&'a self, reader: &'a mut (dyn AsyncRead + Send + Unpin), size: u64) -> BoxFuture<'a, Result<RemoteEntry>>;
+
+    /// Drop content put for `entry` that no manifest swapped in refers to.
+    fn delete<'a>(&'a self, entry: &'a RemoteEntry) -> BoxFuture<'a, Result<()>>;
+
+    /// Replace the manifest with `index` if it is still at `version`,
+    /// described by `message`, deleting content the replaced manifest
+    /// refers to and `index` doesn't. Returns whether it was.
+    fn swap_manifest<'a>(&'a self, version: &'a str, index: &'a RemoteIndex, message: &'a str) -> BoxFuture<'a, Result<bool>>;
+
+    /// The vault's key parameters as published here, if they are.
+    fn key_params(&self) -> BoxFuture<'_, Result<Option<KeyParams>>>;
+
+    /// Publish `params`, unencrypted, so a device joining through this
+    /// backend derives the vault's key from the passphrase.
+    fn publish_key_params<'a>(&'a self, params: &'a KeyParams) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Every file on a remote by vault path: its manifest.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RemoteIndex {
+    pub files: BTreeMap<String, RemoteEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RemoteEntry {
+    /// Where the backend stored the content, in terms only it knows; none
+    /// once the file is deleted.
+    #[serde(default)]
+    pub object: Option<String>,
+    pub size: u64,
+    /// Content hash, or the tombstone's hash for a deleted file.
+    pub hash: [u8; 32],
+    #[serde(default)]
+    pub version: VersionVector,
+    /// When the file was last modified, in seconds since the epoch; 0 for
+    /// entries pushed before it was recorded.
+    #[serde(default)]
+    pub modified: u64,
+    /// Set when the file was deleted, so other devices delete it too.
+    #[serde(default)]
+    pub tombstone: Option<Tombstone>,
+}
+
+impl RemoteEntry {
+    /// Whether `self` should replace `other` in the index: it differs and
+    /// includes every edit `other` does. Entries from before versions were
+    /// recorded are replaced by anything.
+    fn supersedes(&self, other: &RemoteEntry) -> bool {
+        self != other
+            && (other.version == VersionVector::default()
+                || matches!(self.version.compare(&other.version), Causality::After | Causality::Equal))
+    }
+}
+
+/// How long deletions stay in the index for devices that haven't seen them.
+pub const TOMBSTONE_RETENTION_SECS: u64 = 90 * 24 * 60 * 60;
+
+impl RemoteIndex {
+    /// Forget deletions older than `TOMBSTONE_RETENTION_SECS` at `now`.
+    fn prune_tombstones(&mut self, now: u64) {
+        self.files
+            .retain(|_, entry| entry.tombstone.as_ref().is_none_or(|tombstone| !tombstone.expired(now)));
+    }
+}
+
+/// How often a write that raced another is retried.
+pub const MAX_WRITE_ATTEMPTS: u32 = 3;
+
+/// A backend as the engine pushes to it.
+pub struct Remote {
+    pub backend: Arc<dyn RemoteBackend>,
+    /// Held from a push's first put to its swap, so concurrent pushes don't
+    /// replay over each other.
+    pushing: Mutex<()>,
+}
+
+impl Remote {
+    pub fn new(backend: Arc<dyn RemoteBackend>) -> Self {
+        Self {
+            backend,
+            pushing: Mutex::new(()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.backend.name()
+    }
+
+    /// Upload each of `uploads` (a file's index entry and a reader of its
+    /// content) and record each of `deletions` (tombstones) in one manifest
+    /// swap, which deletes the content they replace.
+    ///
+    /// When another device swaps the manifest first, the changes are
+    /// replayed onto its manifest. A path it changed with an edit ours
+    /// doesn't include keeps its entry; pulling it then settles the two.
+    /// Returns those paths, which still have to be pushed once settled.
+    pub async fn push<R: AsyncRead + Send + Unpin>(&self, uploads: Vec<(FileMetadata, R)>, deletions: Vec<FileMetadata>) -> Result<Vec<String>> {
+        let _pushing = self.pushing.lock().await;
+        let mut staged = Vec::new();
+        let mut unused = Vec::new();
+        let put = async {
+            for (meta, mut reader) in uploads {
+                let entry = self.backend.put(&mut reader, meta.size).await?;
+                // Changed since it was indexed; that change is pushed next.
+                if entry.hash != meta.hash {
+                    unused.push(entry);
+                    continue;
+                }
+                staged.push((
+                    meta.path,
+                    RemoteEntry {
+                        version: meta.version,
+                        modified: meta.last_modified,
+                        ..entry
+                    },
+                ));
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        for meta in deletions.into_iter().filter(FileMetadata::is_deleted) {
+            let entry = RemoteEntry {
+                object: None,
+                size: 0,
+                hash: meta.hash,
+                version: meta.version,
+                modified: meta.last_modified,
+                tombstone: meta.tombstone,
+            };
+            staged.push((meta.path, entry));
+        }
+
+        let swapped = match put {
+            Ok(()) => self.swap_in(&staged).await,
+            Err(e) => Err(e),
+        };
+        match &swapped {
+            Ok((left_out, _)) => unused.extend(left_out.iter().cloned()),
+            // Nothing refers to ours if they didn't make it in.
+            Err(_) => unused.extend(staged.into_iter().map(|(_, entry)| entry)),
+        }
+        for entry in &unused {
+            if let Err(e) = self.backend.delete(entry).await {
+                eprintln!("Failed to delete unused content from {}: {}", self.name(), e);
+            }
+        }
+        swapped.map(|(_, contested)| contested)
+    }
+
+    /// Swap `staged` entries into the manifest, replaying them until the
+    /// swap lands. Returns the entries it left out, and the paths of those
+    /// left out for an entry ours doesn't follow.
+    async fn swap_in(&self, staged: &[(String, RemoteEntry)]) -> Result<(Vec<RemoteEntry>, Vec<String>)> {
+        let mut attempt = 1;
+        loop {
+            let (version, mut index) = self.backend.list().await?;
+            let mut left_out = Vec::new();
+            let mut contested = Vec::new();
+            let (mut uploaded, mut deleted) = (0, 0);
+            for (path, entry) in staged {
+                if let Some(current) = index.files.get(path).filter(|current| !entry.supersedes(current)) {
+                    if current != entry {
+                        contested.push(path.clone());
+                    }
+                    left_out.push(entry.clone());
+                    continue;
+                }
+                index.files.insert(path.clone(), entry.clone());
+                match entry.tombstone {
+                    Some(_) => deleted += 1,
+                    None => uploaded += 1,
+                }
+            }
+            if uploaded + deleted == 0 {
+                return Ok((left_out, contested));
+            }
+            index.prune_tombstones(Utc::now().timestamp() as u64);
+
+            let message = format!("Sync {} changed, {} deleted", uploaded, deleted);
+            match self.backend.swap_manifest(&version, &index, &message).await? {
+                true => return Ok((left_out, contested)),
+                false if attempt < MAX_WRITE_ATTEMPTS => attempt += 1,
+                false => return Err(anyhow!("{} kept changing; gave up after {} attempts", self.name(), attempt)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod memory;
+
+#[cfg(test)]
+mod tests {
+    use super::memory::MemoryBackend;
+    use super::{Remote, RemoteBackend, RemoteEntry, RemoteIndex, TOMBSTONE_RETENTION_SECS};
+    use crate::engine::keys::KeyParams;
+    use crate::engine::storage::{FileMetadata, VaultIndexer};
+    use anyhow::Result;
+    use futures::future::BoxFuture;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncRead, AsyncWrite};
+
+    fn edit(indexer: &mut VaultIndexer, path: &str, content: &[u8]) -> FileMetadata {
+        indexer.record_file(path.to_string(), *blake3::hash(content).as_bytes(), content.len() as u64, 0).unwrap();
+        indexer.get_metadata(path).unwrap().clone()
+    }
+
+    /// Has another device swap in its manifest just before our first swap.
+    struct Racing {
+        inner: Arc<MemoryBackend>,
+        rival: Remote,
+        raced: AtomicBool,
+    }
+
+    impl RemoteBackend for Racing {
+        fn name(&self) -> &str {
+            self.inner.name()
+        }
+
+        fn list(&self) -> BoxFuture<'_, Result<(String, RemoteIndex)>> {
+            self.inner.list()
+        }
+
+        fn get<'a>(&'a self, entry: &'a RemoteEntry, writer: &'a mut (dyn AsyncWrite + Send + Unpin)) -> BoxFuture<'a, Result<u64>> {
+            self.inner.get(entry, writer)
+        }
+
+        fn put<'a>(&'a self, reader: &'a mut (dyn AsyncRead + Send + Unpin), size: u64) -> BoxFuture<'a, Result<RemoteEntry>> {
+            self.inner.put(reader, size)
+        }
+
+        fn delete<'a>(&'a self, entry: &'a RemoteEntry) -> BoxFuture<'a, Result<()>> {
+            self.inner.delete(entry)
+        }
+
+        fn swap_manifest<'a>(&'a self, version: &'a str, index: &'a RemoteIndex, message: &'a str) -> BoxFuture<'a, Result<bool>> {
+            Box::pin(async move {
+                if !self.raced.swap(true, Ordering::SeqCst) {
+                    let mut rival = VaultIndexer::new();
+                    rival.set_device_id("rival".to_string());
+                    let theirs = edit(&mut rival, "shared.md", b"from the rival");
+                    self.rival.push(vec![(theirs, &b"from the rival"[..])], Vec::new()).await?;
+                }
+                self.inner.swap_manifest(version, index, message).await
+            })
+        }
+
+        fn key_params(&self) -> BoxFuture<'_, Result<Option<KeyParams>>> {
+            self.inner.key_params()
+        }
+
+        fn publish_key_params<'a>(&'a self, params: &'a KeyParams) -> BoxFuture<'a, Result<()>> {
+            self.inner.publish_key_params(params)
+        }
+    }
+
+    #[tokio::test]
+    async fn pushes_racing_another_device_are_replayed_onto_its_manifest() {
+        let backend = Arc::new(MemoryBackend::new("memory"));
+        let racing = Racing {
+            inner: backend.clone(),
+            rival: Remote::new(backend.clone()),
+            raced: AtomicBool::new(false),
+        };
+        let remote = Remote::new(Arc::new(racing));
+        let mut index = VaultIndexer::new();
+        index.set_device_id("a".to_string());
+
+        // Made without seeing the rival's, so it doesn't replace it; the
+        // other file still goes in on the replay.
+        let ours = edit(&mut index, "shared.md", b"from a");
+        let other = edit(&mut index, "a.md", b"only a");
+        let contested = remote.push(vec![(ours, &b"from a"[..]), (other, &b"only a"[..])], Vec::new()).await.unwrap();
+        assert_eq!(contested, ["shared.md"]);
+        let (_, listed) = backend.list().await.unwrap();
+        assert_eq!(listed.files.keys().collect::<Vec<_>>(), ["a.md", "shared.md"]);
+        assert_eq!(listed.files["shared.md"].hash, *blake3::hash(b"from the rival").as_bytes());
+        // What was left out is deleted.
+        assert_eq!(backend.objects().len(), 2);
+
+        // One made after pulling it replaces it, and its content.
+        index.apply_remote(FileMetadata {
+            version: listed.files["shared.md"].version.clone(),
+            ..index.get_metadata("shared.md").unwrap().clone()
+        }).unwrap();
+        let newer = edit(&mut index, "shared.md", b"a again");
+        assert!(remote.push(vec![(newer, &b"a again"[..])], Vec::new()).await.unwrap().is_empty());
+        let (_, listed) = backend.list().await.unwrap();
+        assert_eq!(listed.files["shared.md"].hash, *blake3::hash(b"a again").as_bytes());
+        assert_eq!(backend.objects().len(), 2);
+
+        let mut content = Vec::new();
+        backend.get(&listed.files["shared.md"], &mut content).await.unwrap();
+        assert_eq!(content, b"a again");
+
+        let tombstone = {
+            index.remove_file("a.md", chrono::Utc::now().timestamp() as u64).unwrap();
+            index.get_metadata("a.md").unwrap().clone()
+        };
+        remote.push(Vec::<(FileMetadata, &[u8])>::new(), vec![tombstone]).await.unwrap();
+        let (_, listed) = backend.list().await.unwrap();
+        assert!(listed.files["a.md"].tombstone.is_some());
+        assert_eq!(backend.objects().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn deletions_seen_only_through_a_remote_are_collected_once_it_forgets_them() {
+        let backend = Arc::new(MemoryBackend::new("memory"));
+        let remote = Remote::new(backend.clone());
+        let mut phone = VaultIndexer::new();
+        phone.set_device_id("phone".to_string());
+        let note = edit(&mut phone, "note.md", b"from the phone");
+        remote.push(vec![(note, &b"from the phone"[..])], Vec::new()).await.unwrap();
+
+        // The laptop only ever hears from the phone through the remote.
+        let mut laptop = VaultIndexer::new();
+        laptop.set_device_id("laptop".to_string());
+        let (_, listed) = backend.list().await.unwrap();
+        let pulled = edit(&mut laptop, "note.md", b"from the phone");
+        laptop.apply_remote(FileMetadata {
+            version: listed.files["note.md"].version.clone(),
+            ..pulled
+        }).unwrap();
+        let deleted_at = chrono::Utc::now().timestamp() as u64;
+        laptop.remove_file("note.md", deleted_at).unwrap();
+        let tombstone = laptop.get_metadata("note.md").unwrap().clone();
+        remote.push(Vec::<(FileMetadata, &[u8])>::new(), vec![tombstone]).await.unwrap();
+
+        // The phone never acknowledges it, but once the remote drops it the
+        // phone can't learn of it anyway.
+        assert!(laptop.collect_garbage(&HashSet::new(), deleted_at).unwrap().is_empty());
+        let expired = deleted_at + TOMBSTONE_RETENTION_SECS;
+        assert_eq!(laptop.collect_garbage(&HashSet::new(), expired).unwrap(), vec!["note.md"]);
+    }
+}
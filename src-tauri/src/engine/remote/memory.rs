@@ -0,0 +1,117 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use super::{RemoteBackend, RemoteEntry, RemoteIndex};
+use crate::engine::keys::KeyParams;
+use crate::engine::version::VersionVector;
+
+/// A backend holding everything in memory, unencrypted.
+pub struct MemoryBackend {
+    name: String,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Bumped by every swap.
+    version: u64,
+    index: RemoteIndex,
+    /// Content the index refers to.
+    objects: HashMap<String, Vec<u8>>,
+    /// Content put but not swapped in yet.
+    pending: HashMap<String, Vec<u8>>,
+    key_params: Option<KeyParams>,
+}
+
+impl MemoryBackend {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Names of every object stored, swapped in or not.
+    pub fn objects(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.objects.keys().chain(state.pending.keys()).cloned().collect()
+    }
+}
+
+impl RemoteBackend for MemoryBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<(String, RemoteIndex)>> {
+        let state = self.state.lock().unwrap();
+        let listed = (state.version.to_string(), state.index.clone());
+        Box::pin(async move { Ok(listed) })
+    }
+
+    fn get<'a>(&'a self, entry: &'a RemoteEntry, writer: &'a mut (dyn AsyncWrite + Send + Unpin)) -> BoxFuture<'a, Result<u64>> {
+        Box::pin(async move {
+            let object = entry.object.as_ref().ok_or_else(|| anyhow!("no content to get"))?;
+            let content = self.state.lock().unwrap().objects.get(object).cloned();
+            let content = content.ok_or_else(|| anyhow!("{} is missing", object))?;
+            writer.write_all(&content).await?;
+            writer.flush().await?;
+            Ok(content.len() as u64)
+        })
+    }
+
+    fn put<'a>(&'a self, reader: &'a mut (dyn AsyncRead + Send + Unpin), _size: u64) -> BoxFuture<'a, Result<RemoteEntry>> {
+        Box::pin(async move {
+            let mut content = Vec::new();
+            reader.read_to_end(&mut content).await?;
+            let object = uuid::Uuid::new_v4().simple().to_string();
+            let entry = RemoteEntry {
+                object: Some(object.clone()),
+                size: content.len() as u64,
+                hash: *blake3::hash(&content).as_bytes(),
+                version: VersionVector::default(),
+                modified: 0,
+                tombstone: None,
+            };
+            self.state.lock().unwrap().pending.insert(object, content);
+            Ok(entry)
+        })
+    }
+
+    fn delete<'a>(&'a self, entry: &'a RemoteEntry) -> BoxFuture<'a, Result<()>> {
+        if let Some(object) = &entry.object {
+            self.state.lock().unwrap().pending.remove(object);
+        }
+        Box::pin(async { Ok(()) })
+    }
+
+    fn swap_manifest<'a>(&'a self, version: &'a str, index: &'a RemoteIndex, _message: &'a str) -> BoxFuture<'a, Result<bool>> {
+        let mut state = self.state.lock().unwrap();
+        let swapped = state.version.to_string() == version;
+        if swapped {
+            let live: HashSet<&String> = index.files.values().filter_map(|entry| entry.object.as_ref()).collect();
+            let State { objects, pending, .. } = &mut *state;
+            objects.retain(|object, _| live.contains(object));
+            for object in live {
+                if let Some(content) = pending.remove(object) {
+                    objects.insert(object.clone(), content);
+                }
+            }
+            state.version += 1;
+            state.index = index.clone();
+        }
+        Box::pin(async move { Ok(swapped) })
+    }
+
+    fn key_params(&self) -> BoxFuture<'_, Result<Option<KeyParams>>> {
+        let params = self.state.lock().unwrap().key_params.clone();
+        Box::pin(async move { Ok(params) })
+    }
+
+    fn publish_key_params<'a>(&'a self, params: &'a KeyParams) -> BoxFuture<'a, Result<()>> {
+        self.state.lock().unwrap().key_params = Some(params.clone());
+        Box::pin(async { Ok(()) })
+    }
+}
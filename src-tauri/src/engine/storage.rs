@@ -1,7 +1,62 @@
 use anyhow::Result;
+use merkle_search_tree::builder::Builder;
+use merkle_search_tree::digest::{Digest, Hasher};
 use merkle_search_tree::MerkleSearchTree;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::Path;
+use crate::engine::index_store::IndexStore;
+use crate::engine::remote::TOMBSTONE_RETENTION_SECS;
+use crate::engine::version::{Causality, VersionVector};
+
+pub type VaultTree = MerkleSearchTree<String, [u8; 32], VaultHasher>;
+
+/// Key/value hasher for the vault MST.
+///
+/// The crate default hashes through `std::hash::Hash`, which writes
+/// platform-sized length prefixes, so a 32-bit phone and a 64-bit desktop
+/// would never agree on a root hash. Truncated blake3 is stable everywhere.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VaultHasher;
+
+impl Hasher<16, String> for VaultHasher {
+    fn hash(&self, value: &String) -> Digest<16> {
+        truncated_digest(value.as_bytes())
+    }
+}
+
+impl Hasher<16, [u8; 32]> for VaultHasher {
+    fn hash(&self, value: &[u8; 32]) -> Digest<16> {
+        truncated_digest(value)
+    }
+}
+
+fn truncated_digest(bytes: &[u8]) -> Digest<16> {
+    let mut out = [0u8; 16];
+    out.copy_from_slice(&blake3::hash(bytes).as_bytes()[..16]);
+    Digest::new(out)
+}
+
+/// Serialised form of one MST page, exchanged with peers during a diff.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct PageRangeDigest {
+    pub start: String,
+    pub end: String,
+    pub hash: [u8; 16],
+}
+
+/// Inclusive range of paths whose content is identical on two indexers.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KeyRange {
+    pub start: String,
+    pub end: String,
+}
+
+impl KeyRange {
+    pub fn contains(&self, path: &str) -> bool {
+        self.start.as_str() <= path && path <= self.end.as_str()
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct FileMetadata {
@@ -9,19 +64,193 @@ pub struct FileMetadata {
     pub size: u64,
     pub hash: [u8; 32],
     pub last_modified: u64,
+    /// Iroh hash of the hash sequence listing the content's encrypted
+    /// chunk blobs, which peers fetch this content from.
+    #[serde(default)]
+    pub blob: Option<[u8; 32]>,
+    /// The content's chunks in order, as listed by `blob`.
+    #[serde(default)]
+    pub chunks: Vec<Chunk>,
+    #[serde(default)]
+    pub version: VersionVector,
+    /// Earlier contents, newest first, kept so a three-way merge can find
+    /// the last version both sides had.
+    #[serde(default)]
+    pub history: Vec<Revision>,
+    /// Set when the file has been deleted; `hash` then identifies the
+    /// tombstone rather than any content.
+    #[serde(default)]
+    pub tombstone: Option<Tombstone>,
+    /// Set when this entry was created by moving another path, so peers that
+    /// still have the old path can rename it instead of downloading it again.
+    #[serde(default)]
+    pub moved_from: Option<String>,
+}
+
+/// Record of a deletion, kept in the index and the MST so it reaches every
+/// peer instead of the file being resurrected by one that still has it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Tombstone {
+    pub deleted_at: u64,
+    pub device: String,
+    /// Devices known to have stored this tombstone. It is garbage-collected
+    /// once every known device is in here.
+    pub acked: BTreeSet<String>,
+    /// Where the file went, when the deletion was the source side of a move.
+    #[serde(default)]
+    pub moved_to: Option<String>,
+}
+
+impl Tombstone {
+    /// Whether remotes have dropped the tombstone by `now`, so a device
+    /// that only syncs through them can no longer learn of it.
+    pub fn expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.deleted_at) >= TOMBSTONE_RETENTION_SECS
+    }
+
+    /// MST value for the tombstone. Only the acknowledgements go in, so two
+    /// devices agree on it as soon as they have exchanged acks.
+    fn hash(&self) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(b"oversync-tombstone");
+        for device in &self.acked {
+            hasher.update(device.as_bytes());
+            hasher.update(&[0]);
+        }
+        hasher.finalize().into()
+    }
+}
+
+/// One content-defined chunk of a file.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Chunk {
+    /// blake3 of the plaintext, shared by every file containing the chunk.
+    pub hash: [u8; 32],
+    /// Iroh hash of the encrypted chunk.
+    pub blob: [u8; 32],
+    pub size: u64,
+    /// Key epoch the chunk was encrypted under.
+    #[serde(default)]
+    pub epoch: u32,
+}
+
+/// A previous content hash of a file and the blob it can be read from.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Revision {
+    pub hash: [u8; 32],
+    pub blob: Option<[u8; 32]>,
+}
+
+/// How many earlier revisions each file remembers.
+pub const HISTORY_LEN: usize = 8;
+
+impl FileMetadata {
+    pub fn is_deleted(&self) -> bool {
+        self.tombstone.is_some()
+    }
+
+    /// Whether the content is stored as chunks. Entries indexed before
+    /// chunking point at a single encrypted blob instead.
+    pub fn is_chunked(&self) -> bool {
+        self.blob.is_some() && (self.size == 0 || !self.chunks.is_empty())
+    }
+
+    pub fn revision(&self) -> Revision {
+        Revision {
+            hash: self.hash,
+            blob: self.blob,
+        }
+    }
+
+    /// The current revision followed by the history, newest first.
+    pub fn revisions(&self) -> impl Iterator<Item = Revision> + '_ {
+        std::iter::once(self.revision())
+            .filter(|_| !self.is_deleted())
+            .chain(self.history.iter().cloned())
+    }
 }
 
 pub struct VaultIndexer {
-    pub(crate) mst: MerkleSearchTree<String, [u8; 32]>,
+    pub(crate) mst: VaultTree,
     pub(crate) metadata: HashMap<String, FileMetadata>,
+    store: Option<IndexStore>,
+    /// Counter bumped in a file's version vector on local edits.
+    device_id: String,
+    /// Peers that have to acknowledge a tombstone before it is dropped.
+    known_devices: BTreeSet<String>,
+    /// Plaintext chunk hash -> encrypted chunk, so content shared between
+    /// files or versions is stored and transferred once.
+    chunk_blobs: HashMap<[u8; 32], Chunk>,
+}
+
+impl Default for VaultIndexer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VaultIndexer {
     pub fn new() -> Self {
         Self {
-            mst: MerkleSearchTree::default(),
+            mst: new_tree(),
             metadata: HashMap::new(),
+            store: None,
+            device_id: "local".to_string(),
+            known_devices: BTreeSet::new(),
+            chunk_blobs: HashMap::new(),
+        }
+    }
+
+    /// Open the index persisted at `path`, creating it if needed. Every
+    /// later update is written through before it is applied in memory.
+    pub fn open(path: &Path) -> Result<Self> {
+        let store = IndexStore::open(path)?;
+        let metadata = store.load()?;
+        let known_devices = store.load_devices()?;
+
+        let mut indexer = Self {
+            mst: new_tree(),
+            metadata,
+            store: Some(store),
+            device_id: "local".to_string(),
+            known_devices,
+            chunk_blobs: HashMap::new(),
+        };
+        indexer.rebuild_mst();
+        for meta in indexer.metadata.values() {
+            for chunk in &meta.chunks {
+                indexer.chunk_blobs.insert(chunk.hash, *chunk);
+            }
         }
+        Ok(indexer)
+    }
+
+    pub fn set_device_id(&mut self, device_id: String) {
+        self.device_id = device_id;
+    }
+
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    /// Remember a peer so tombstones wait for its acknowledgement.
+    pub fn add_device(&mut self, device: &str) -> Result<()> {
+        if self.known_devices.insert(device.to_string()) {
+            if let Some(store) = &self.store {
+                store.add_device(device)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `device` has been paired with, so may sync with us.
+    pub fn is_known_device(&self, device: &str) -> bool {
+        self.known_devices.contains(device)
+    }
+
+    /// Every device paired with, including in earlier runs.
+    pub fn known_devices(&self) -> impl Iterator<Item = &str> {
+        self.known_devices.iter().map(String::as_str)
     }
 
     pub fn update_file(
@@ -30,27 +259,288 @@ impl VaultIndexer {
         content: &[u8],
         last_modified: u64,
     ) -> Result<[u8; 32]> {
-        let hash = blake3::hash(content);
-        let hash_bytes: [u8; 32] = hash.into();
+        self.record_file(path, blake3::hash(content).into(), content.len() as u64, last_modified)
+    }
+
+    /// [`VaultIndexer::update_file`] for content hashed by the caller, so
+    /// large files never have to be read into memory.
+    pub fn record_file(
+        &mut self,
+        path: String,
+        hash: [u8; 32],
+        size: u64,
+        last_modified: u64,
+    ) -> Result<[u8; 32]> {
+        let existing = self.metadata.get(&path);
+        let (blob, chunks) = existing
+            .filter(|existing| existing.hash == hash)
+            .map(|existing| (existing.blob, existing.chunks.clone()))
+            .unwrap_or_default();
+        let mut version = existing.map(|existing| existing.version.clone()).unwrap_or_default();
+        let moved_from = existing
+            .filter(|existing| existing.hash == hash)
+            .and_then(|existing| existing.moved_from.clone());
+        let history = match existing {
+            Some(existing) if existing.hash != hash => {
+                let mut history: Vec<Revision> = existing.revisions().collect();
+                history.truncate(HISTORY_LEN);
+                history
+            }
+            Some(existing) => existing.history.clone(),
+            None => Vec::new(),
+        };
+        if existing.is_none_or(|existing| existing.hash != hash) {
+            version.increment(&self.device_id);
+        }
 
         let meta = FileMetadata {
-            path: path.clone(),
-            size: content.len() as u64,
-            hash: hash_bytes,
+            path,
+            size,
+            hash,
             last_modified,
+            blob,
+            chunks,
+            version,
+            history,
+            tombstone: None,
+            moved_from,
         };
 
-        self.mst.upsert(path.clone(), &hash_bytes);
-        self.metadata.insert(path, meta);
+        self.put(meta)
+    }
 
-        Ok(self.root_hash())
+    /// Store metadata received from a peer, keeping its version.
+    ///
+    /// History entries we hold a blob for locally keep pointing at it, since
+    /// the peer's blobs for older revisions may never have been fetched. A
+    /// tombstone is acknowledged by this device and merged with any local one.
+    pub fn apply_remote(&mut self, mut meta: FileMetadata) -> Result<[u8; 32]> {
+        if let Some(tombstone) = &mut meta.tombstone {
+            tombstone.acked.insert(self.device_id.clone());
+            if let Some(local) = self.metadata.get(&meta.path) {
+                if let Some(local_tombstone) = &local.tombstone {
+                    tombstone.acked.extend(local_tombstone.acked.iter().cloned());
+                    meta.version.merge(&local.version);
+                }
+            }
+            meta.hash = tombstone.hash();
+        }
+
+        if let Some(local) = self.metadata.get(&meta.path) {
+            for revision in &mut meta.history {
+                let known = local
+                    .revisions()
+                    .find(|known| known.hash == revision.hash && known.blob.is_some());
+                if let Some(known) = known {
+                    revision.blob = known.blob;
+                }
+            }
+        }
+        self.put(meta)
     }
 
-    pub fn remove_file(&mut self, path: &str) -> Result<[u8; 32]> {
-        self.metadata.remove(path);
+    /// Index the result of merging `remote` into the local `path`.
+    ///
+    /// The merged version dominates both sides and both parents go into the
+    /// history, so either one can serve as the ancestor of a later merge.
+    pub fn record_merge(
+        &mut self,
+        path: &str,
+        content: &[u8],
+        last_modified: u64,
+        remote: &FileMetadata,
+    ) -> Result<[u8; 32]> {
+        let local = self
+            .metadata
+            .get(path)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("{} is not indexed", path))?;
+
+        let mut history: Vec<Revision> = Vec::new();
+        for revision in local.revisions().chain(remote.revisions()) {
+            if !history.iter().any(|seen| seen.hash == revision.hash) {
+                history.push(revision);
+            }
+        }
+        history.truncate(HISTORY_LEN);
+
+        let mut version = local.version.merged(&remote.version);
+        version.increment(&self.device_id);
+
+        self.put(FileMetadata {
+            path: path.to_string(),
+            size: content.len() as u64,
+            hash: blake3::hash(content).into(),
+            last_modified,
+            blob: None,
+            chunks: Vec::new(),
+            version,
+            history,
+            tombstone: None,
+            moved_from: None,
+        })
+    }
+
+    /// Make the local version of `path` dominate `other`, after a conflict
+    /// was resolved in favour of the local content.
+    pub fn supersede(&mut self, path: &str, other: &VersionVector) -> Result<()> {
+        if let Some(mut meta) = self.metadata.get(path).cloned() {
+            meta.version.merge(other);
+            meta.version.increment(&self.device_id);
+            self.put(meta)?;
+        }
+        Ok(())
+    }
+
+    fn put(&mut self, meta: FileMetadata) -> Result<[u8; 32]> {
+        if let Some(store) = &self.store {
+            store.put(&meta)?;
+        }
+        self.mst.upsert(meta.path.clone(), &meta.hash);
+        for chunk in &meta.chunks {
+            self.chunk_blobs.insert(chunk.hash, *chunk);
+        }
+        self.metadata.insert(meta.path.clone(), meta);
+
         Ok(self.root_hash())
     }
 
+    /// Replace a locally deleted file with a tombstone.
+    pub fn remove_file(&mut self, path: &str, deleted_at: u64) -> Result<[u8; 32]> {
+        let Some(existing) = self.metadata.get(path).filter(|meta| !meta.is_deleted()) else {
+            return Ok(self.root_hash());
+        };
+
+        let tombstone = self.tombstone_for(existing, deleted_at, None);
+        self.put(tombstone)
+    }
+
+    /// Record a local rename of `from` to `to` as one move: `to` takes over
+    /// the content and blob, and `from` becomes a tombstone pointing at it.
+    pub fn move_file(&mut self, from: &str, to: &str, moved_at: u64) -> Result<[u8; 32]> {
+        let Some(existing) = self.metadata.get(from).filter(|meta| !meta.is_deleted()) else {
+            return Ok(self.root_hash());
+        };
+
+        let tombstone = self.tombstone_for(existing, moved_at, Some(to.to_string()));
+        let mut moved = existing.clone();
+        moved.path = to.to_string();
+        moved.moved_from = Some(from.to_string());
+        if let Some(target) = self.metadata.get(to) {
+            // Supersede whatever was at the destination before.
+            moved.version.merge(&target.version);
+        }
+        moved.version.increment(&self.device_id);
+
+        self.put(tombstone)?;
+        self.put(moved)
+    }
+
+    fn tombstone_for(&self, existing: &FileMetadata, deleted_at: u64, moved_to: Option<String>) -> FileMetadata {
+        let tombstone = Tombstone {
+            deleted_at,
+            device: self.device_id.clone(),
+            acked: BTreeSet::from([self.device_id.clone()]),
+            moved_to,
+        };
+        let mut version = existing.version.clone();
+        version.increment(&self.device_id);
+        let mut history: Vec<Revision> = existing.revisions().collect();
+        history.truncate(HISTORY_LEN);
+
+        FileMetadata {
+            path: existing.path.clone(),
+            size: 0,
+            hash: tombstone.hash(),
+            last_modified: deleted_at,
+            blob: None,
+            chunks: Vec::new(),
+            version,
+            history,
+            tombstone: Some(tombstone),
+            moved_from: None,
+        }
+    }
+
+    /// Whether a peer's move of `source` to `entry` can be replayed here as
+    /// a plain rename: we still have the source with the same content, the
+    /// move happened after our last edit to it, and nothing live is in the way.
+    pub fn can_rename(&self, entry: &FileMetadata, source: &FileMetadata) -> bool {
+        let Some(local) = self.metadata.get(&source.path).filter(|meta| !meta.is_deleted()) else {
+            return false;
+        };
+        local.hash == entry.hash
+            && local.version.compare(&source.version) == Causality::Before
+            && self.metadata.get(&entry.path).is_none_or(|target| target.is_deleted())
+    }
+
+    /// Whether every device that could still hold `path` has acknowledged
+    /// its tombstone at `now`. Devices that aren't peers only see it through
+    /// remotes and never acknowledge it; they count once remotes drop it.
+    pub fn fully_acked(&self, meta: &FileMetadata, now: u64) -> bool {
+        let Some(tombstone) = &meta.tombstone else {
+            return false;
+        };
+        let peers_acked = std::iter::once(&self.device_id)
+            .chain(&self.known_devices)
+            .all(|device| tombstone.acked.contains(device));
+        peers_acked
+            && (tombstone.expired(now) || meta.version.devices().all(|device| tombstone.acked.contains(device)))
+    }
+
+    /// Drop tombstones every known device has acknowledged at `now`, but
+    /// those of `pending` paths, which have yet to reach a remote.
+    pub fn collect_garbage(&mut self, pending: &HashSet<String>, now: u64) -> Result<Vec<String>> {
+        let settled: Vec<String> = self
+            .metadata
+            .values()
+            .filter(|meta| self.fully_acked(meta, now) && !pending.contains(&meta.path))
+            .map(|meta| meta.path.clone())
+            .collect();
+        self.purge(&settled)?;
+        Ok(settled)
+    }
+
+    /// Stop tracking paths matching `ignored` without tombstoning them, so
+    /// peers keep their copies.
+    pub fn forget<F>(&mut self, ignored: F) -> Result<Vec<String>>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let forgotten: Vec<String> = self
+            .metadata
+            .keys()
+            .filter(|path| ignored(path))
+            .cloned()
+            .collect();
+        self.purge(&forgotten)?;
+        Ok(forgotten)
+    }
+
+    fn purge(&mut self, paths: &[String]) -> Result<()> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+
+        for path in paths {
+            if let Some(store) = &self.store {
+                store.delete(path)?;
+            }
+            self.metadata.remove(path);
+        }
+        // The MST has no delete operation, so rebuild it from what's left.
+        self.rebuild_mst();
+        Ok(())
+    }
+
+    fn rebuild_mst(&mut self) {
+        let mut mst = new_tree();
+        for (path, meta) in &self.metadata {
+            mst.upsert(path.clone(), &meta.hash);
+        }
+        self.mst = mst;
+    }
+
     pub fn root_hash(&mut self) -> [u8; 32] {
         let root_hash = self.mst.root_hash();
         let mut bytes = [0u8; 32];
@@ -62,21 +552,156 @@ impl VaultIndexer {
         self.metadata.get(path)
     }
 
-    pub fn diff(&self, _other_mst: &MerkleSearchTree<String, [u8; 32]>) -> Vec<String> {
-        let changes = Vec::new();
-        // The diff API in 0.8.0 is more complex, requiring serialised page ranges.
-        // For now, return empty as we need more logic to implement this properly.
-        changes
+    /// An encrypted chunk already stored with this plaintext hash.
+    pub fn chunk_blob(&self, hash: &[u8; 32]) -> Option<Chunk> {
+        self.chunk_blobs.get(hash).copied()
+    }
+
+    /// Live files with chunks encrypted under an epoch older than `epoch`.
+    pub fn stale_files(&self, epoch: u32) -> Vec<FileMetadata> {
+        self.metadata
+            .values()
+            .filter(|meta| !meta.is_deleted() && meta.chunks.iter().any(|chunk| chunk.epoch < epoch))
+            .cloned()
+            .collect()
     }
 
-    pub fn get_mst(&self) -> &MerkleSearchTree<String, [u8; 32]> {
+    /// Record the blob holding `hash`, whether that is still the current
+    /// content of `path` or has already moved into its history.
+    pub fn set_blob(&mut self, path: &str, hash: [u8; 32], blob: [u8; 32], chunks: &[Chunk]) -> Result<()> {
+        for chunk in chunks {
+            self.chunk_blobs.insert(chunk.hash, *chunk);
+        }
+        if let Some(meta) = self.metadata.get_mut(path) {
+            let mut found = false;
+            if meta.hash == hash {
+                meta.blob = Some(blob);
+                meta.chunks = chunks.to_vec();
+                found = true;
+            }
+            for revision in meta.history.iter_mut().filter(|revision| revision.hash == hash) {
+                revision.blob = Some(blob);
+                found = true;
+            }
+            if found {
+                if let Some(store) = &self.store {
+                    store.put(meta)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Serialise the MST pages so a peer can compare them against its own.
+    pub fn page_ranges(&mut self) -> Vec<PageRangeDigest> {
+        self.mst.root_hash();
+        self.mst
+            .serialise_page_ranges()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|page| PageRangeDigest {
+                start: page.start().clone(),
+                end: page.end().clone(),
+                hash: *page.hash().as_bytes(),
+            })
+            .collect()
+    }
+
+    /// Key ranges proven identical between this indexer and `peer`.
+    ///
+    /// An MST subtree holds every key between its first and last key, so a
+    /// page with the same bounds and digest on both sides means that whole
+    /// range matches. Only entries outside these ranges need comparing.
+    pub fn consistent_ranges(&mut self, peer: &[PageRangeDigest]) -> Vec<KeyRange> {
+        let local = self.page_ranges();
+        let peer: HashSet<&PageRangeDigest> = peer.iter().collect();
+        let mut matched: Vec<&PageRangeDigest> =
+            local.iter().filter(|page| peer.contains(page)).collect();
+        matched.sort_by(|a, b| a.start.cmp(&b.start).then(b.end.cmp(&a.end)));
+
+        let mut ranges: Vec<KeyRange> = Vec::new();
+        for page in matched {
+            if ranges.last().is_some_and(|last| last.contains(&page.end)) {
+                continue;
+            }
+            ranges.push(KeyRange {
+                start: page.start.clone(),
+                end: page.end.clone(),
+            });
+        }
+        ranges
+    }
+
+    /// Content hashes for every indexed path not covered by `consistent`.
+    pub fn entries_outside(&self, consistent: &[KeyRange]) -> BTreeMap<String, [u8; 32]> {
+        self.metadata
+            .iter()
+            .filter(|(path, _)| !consistent.iter().any(|range| range.contains(path)))
+            .map(|(path, meta)| (path.clone(), meta.hash))
+            .collect()
+    }
+
+    /// Paths that were added, changed or removed relative to the peer, given
+    /// the agreed `consistent` ranges and the peer's entries outside them.
+    pub fn compare_entries(
+        &self,
+        consistent: &[KeyRange],
+        entries: &BTreeMap<String, [u8; 32]>,
+    ) -> Vec<String> {
+        let local = self.entries_outside(consistent);
+        let mut changed: BTreeSet<String> = BTreeSet::new();
+
+        for (path, hash) in entries {
+            if local.get(path) != Some(hash) {
+                changed.insert(path.clone());
+            }
+        }
+        for path in local.keys() {
+            if !entries.contains_key(path) {
+                changed.insert(path.clone());
+            }
+        }
+
+        changed.into_iter().collect()
+    }
+
+    /// Exact set of paths that differ between this indexer and `other`.
+    ///
+    /// Runs both sides of the page range exchange in-process; the P2P
+    /// protocol performs the same steps over the wire.
+    pub fn diff(&mut self, other: &mut VaultIndexer) -> Vec<String> {
+        let ours = self.page_ranges();
+        let consistent = other.consistent_ranges(&ours);
+        let peer_entries = other.entries_outside(&consistent);
+        self.compare_entries(&consistent, &peer_entries)
+    }
+
+    pub fn get_mst(&self) -> &VaultTree {
         &self.mst
     }
 }
 
+/// The tombstone in `changes` that `entry` was moved from, if any.
+pub fn move_source<'a>(entry: &FileMetadata, changes: &'a [FileMetadata]) -> Option<&'a FileMetadata> {
+    let from = entry.moved_from.as_deref()?;
+    changes.iter().find(|meta| {
+        meta.path == from
+            && meta
+                .tombstone
+                .as_ref()
+                .is_some_and(|tombstone| tombstone.moved_to.as_deref() == Some(entry.path.as_str()))
+    })
+}
+
+fn new_tree() -> VaultTree {
+    Builder::default().with_hasher(VaultHasher).build()
+}
+
 #[cfg(test)]
 mod tests {
     use super::VaultIndexer;
+    use proptest::prelude::*;
+    use std::collections::{BTreeMap, BTreeSet, HashSet};
 
     #[test]
     fn root_hash_changes_on_update() {
@@ -115,4 +740,176 @@ mod tests {
         assert_eq!(metadata.size, content.len() as u64);
         assert_eq!(metadata.last_modified, 42);
     }
+
+    #[test]
+    fn version_only_bumps_when_content_changes() {
+        let mut indexer = VaultIndexer::new();
+        indexer.set_device_id("laptop".to_string());
+
+        indexer.update_file("note.md".to_string(), b"v1", 0).unwrap();
+        indexer.update_file("note.md".to_string(), b"v1", 1).unwrap();
+        assert_eq!(indexer.get_metadata("note.md").unwrap().version.get("laptop"), 1);
+
+        indexer.update_file("note.md".to_string(), b"v2", 2).unwrap();
+        assert_eq!(indexer.get_metadata("note.md").unwrap().version.get("laptop"), 2);
+    }
+
+    #[test]
+    fn edits_keep_previous_revisions() {
+        let mut indexer = VaultIndexer::new();
+        let v1: [u8; 32] = blake3::hash(b"v1").into();
+
+        indexer.update_file("note.md".to_string(), b"v1", 0).unwrap();
+        indexer.update_file("note.md".to_string(), b"v2", 1).unwrap();
+        indexer.set_blob("note.md", v1, [7; 32], &[]).unwrap();
+
+        let history = &indexer.get_metadata("note.md").unwrap().history;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].hash, v1);
+        assert_eq!(history[0].blob, Some([7; 32]));
+
+        for i in 0..2 * super::HISTORY_LEN {
+            indexer.update_file("note.md".to_string(), format!("v{}", i + 3).as_bytes(), 2).unwrap();
+        }
+        assert_eq!(indexer.get_metadata("note.md").unwrap().history.len(), super::HISTORY_LEN);
+    }
+
+    #[test]
+    fn root_hash_changes_on_remove() {
+        let mut indexer = VaultIndexer::new();
+        let empty_hash = indexer.root_hash();
+
+        let hash_after_add = indexer
+            .update_file("note.md".to_string(), b"hello", 0)
+            .expect("update should succeed");
+        let hash_after_remove = indexer
+            .remove_file("note.md", 1)
+            .expect("remove should succeed");
+
+        assert_ne!(hash_after_add, hash_after_remove);
+        assert_ne!(empty_hash, hash_after_remove);
+        assert!(indexer.get_metadata("note.md").unwrap().is_deleted());
+    }
+
+    #[test]
+    fn moves_replay_as_renames_on_peers() {
+        let mut laptop = VaultIndexer::new();
+        laptop.set_device_id("laptop".to_string());
+        let mut phone = VaultIndexer::new();
+        phone.set_device_id("phone".to_string());
+
+        laptop.update_file("note.md".to_string(), b"hello", 0).unwrap();
+        phone.apply_remote(laptop.get_metadata("note.md").unwrap().clone()).unwrap();
+        laptop.move_file("note.md", "folder/note.md", 1).unwrap();
+
+        let changes: Vec<_> = laptop
+            .diff(&mut phone)
+            .iter()
+            .map(|path| laptop.get_metadata(path).unwrap().clone())
+            .collect();
+        let entry = changes.iter().find(|meta| !meta.is_deleted()).unwrap();
+        assert_eq!(entry.moved_from.as_deref(), Some("note.md"));
+
+        let source = super::move_source(entry, &changes).expect("tombstone should pair with the move");
+        assert!(phone.can_rename(entry, source));
+
+        phone.apply_remote(entry.clone()).unwrap();
+        phone.apply_remote(source.clone()).unwrap();
+        assert!(!phone.can_rename(entry, source));
+        assert!(phone.get_metadata("note.md").unwrap().is_deleted());
+    }
+
+    #[test]
+    fn tombstones_are_collected_once_every_device_acked() {
+        let mut laptop = VaultIndexer::new();
+        laptop.set_device_id("laptop".to_string());
+        laptop.add_device("phone").unwrap();
+        let empty_hash = laptop.root_hash();
+
+        laptop.update_file("note.md".to_string(), b"hello", 0).unwrap();
+        laptop.remove_file("note.md", 1).unwrap();
+        assert!(laptop.collect_garbage(&HashSet::new(), 1).unwrap().is_empty());
+
+        let mut phone = VaultIndexer::new();
+        phone.set_device_id("phone".to_string());
+        phone.apply_remote(laptop.get_metadata("note.md").unwrap().clone()).unwrap();
+        laptop.apply_remote(phone.get_metadata("note.md").unwrap().clone()).unwrap();
+        assert_eq!(laptop.root_hash(), phone.root_hash());
+
+        // Not before it is pushed to the remotes.
+        let pending = HashSet::from(["note.md".to_string()]);
+        assert!(laptop.collect_garbage(&pending, 1).unwrap().is_empty());
+        assert_eq!(laptop.collect_garbage(&HashSet::new(), 1).unwrap(), vec!["note.md"]);
+        assert_eq!(laptop.root_hash(), empty_hash);
+    }
+
+    #[test]
+    fn diff_reports_added_changed_and_removed() {
+        let mut local = VaultIndexer::new();
+        let mut remote = VaultIndexer::new();
+
+        for indexer in [&mut local, &mut remote] {
+            indexer.update_file("same.md".to_string(), b"same", 0).unwrap();
+            indexer.update_file("edited.md".to_string(), b"v1", 0).unwrap();
+        }
+        remote.update_file("edited.md".to_string(), b"v2", 1).unwrap();
+        remote.update_file("new.md".to_string(), b"new", 1).unwrap();
+        local.update_file("gone.md".to_string(), b"gone", 0).unwrap();
+
+        let changes = local.diff(&mut remote);
+
+        assert_eq!(changes, vec!["edited.md", "gone.md", "new.md"]);
+        assert_eq!(remote.diff(&mut local), changes);
+    }
+
+    #[test]
+    fn reopened_index_keeps_metadata_and_root() {
+        let path = std::env::temp_dir()
+            .join(format!("oversync-index-{}", uuid::Uuid::new_v4()))
+            .join("index.redb");
+
+        let root = {
+            let mut indexer = VaultIndexer::open(&path).expect("open should succeed");
+            indexer.update_file("a.md".to_string(), b"a", 1).unwrap();
+            indexer.update_file("b.md".to_string(), b"b", 2).unwrap();
+            indexer.remove_file("a.md", 3).unwrap()
+        };
+
+        let mut reopened = VaultIndexer::open(&path).expect("reopen should succeed");
+        assert_eq!(reopened.root_hash(), root);
+        assert!(reopened.get_metadata("a.md").unwrap().is_deleted());
+        assert_eq!(reopened.get_metadata("b.md").unwrap().last_modified, 2);
+    }
+
+    fn build(files: &BTreeMap<String, u8>) -> VaultIndexer {
+        let mut indexer = VaultIndexer::new();
+        for (path, content) in files {
+            indexer.update_file(path.clone(), &[*content], 0).unwrap();
+        }
+        indexer
+    }
+
+    proptest! {
+        #[test]
+        fn diff_matches_brute_force(
+            a in prop::collection::btree_map("[a-z]{1,6}\\.md", 0u8..4, 0..64),
+            b in prop::collection::btree_map("[a-z]{1,6}\\.md", 0u8..4, 0..64),
+        ) {
+            let mut local = build(&a);
+            let mut remote = build(&b);
+
+            let expected: Vec<String> = local
+                .metadata
+                .keys()
+                .chain(remote.metadata.keys())
+                .filter(|path| local.metadata.get(*path) != remote.metadata.get(*path))
+                .cloned()
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect();
+
+            prop_assert_eq!(local.diff(&mut remote), expected.clone());
+            prop_assert_eq!(remote.diff(&mut local), expected);
+        }
+    }
 }
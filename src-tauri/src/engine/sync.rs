@@ -1,71 +1,297 @@
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
-use anyhow::Result;
-use tokio::sync::{mpsc, RwLock};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::{mpsc, Mutex, Notify, RwLock, Semaphore};
 use notify::Event;
+use serde::{Deserialize, Serialize};
 use crate::engine::p2p::{P2pNode, P2pEvent};
-use crate::engine::storage::VaultIndexer;
+use crate::engine::storage::{move_source, Chunk, FileMetadata, VaultIndexer, HISTORY_LEN};
+use crate::engine::chunker::ChunkReader;
+use crate::engine::conflict::{conflict_copy_path, ConflictRecord, ConflictStrategy};
+use crate::engine::merge::merge_markdown;
+use crate::engine::queue::ChangeQueue;
+use crate::engine::ignore_rules::{IgnoreRules, IGNORE_FILE, LOCAL_IGNORE_FILE};
+use crate::engine::obsidian_config::{ConfigSyncSettings, CONFIG_SYNC_FILE};
+use crate::engine::version::{Causality, VersionVector};
 use crate::engine::watcher::VaultWatcher;
 use crate::engine::github::GitHubStorage;
-use crate::engine::encryption::Encryptor;
-use crate::engine::{SyncStatus, GithubConfig};
+use crate::engine::remote::{Remote, RemoteBackend, RemoteEntry};
+use crate::engine::encryption::{Binding, EncryptionMode, Encryptor};
+use crate::engine::keys::{KeyParams, KeyRing, KEY_PARAMS_FILE};
+use crate::engine::{RotationProgress, ScanProgress, SyncStatus, GithubConfig};
 use chrono::Utc;
 
+/// How often every known peer is asked whether our vaults have diverged.
+const ANTI_ENTROPY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Suffix for the temp file a remote change is written to before the rename.
+const TEMP_SUFFIX: &str = ".oversync-tmp";
+
+/// How long watcher events for a file we just wrote are treated as our own.
+const ECHO_WINDOW: Duration = Duration::from_secs(5);
+
+/// How long a deletion is held back in case a file with the same content
+/// shows up elsewhere, making it a move.
+const MOVE_WINDOW: Duration = Duration::from_secs(2);
+
+/// Raw watcher events buffered before the notify thread is made to wait.
+const EVENT_QUEUE_CAPACITY: usize = 1024;
+
+/// How often settled changes and expired removals are processed.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Blob imports and remote requests allowed to run at once.
+const MAX_CONCURRENT_UPLOADS: usize = 4;
+
+/// How often queued changes are pushed to each remote, as one manifest
+/// swap, and changes made there by other devices pulled.
+const REMOTE_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Follows a remote's name to name the file in the data dir its push queue
+/// is kept in, so changes that weren't pushed before a restart still are.
+const PUSH_QUEUE_SUFFIX: &str = "_queue.json";
+
+/// How long a push queue is left unchanged before it is saved, so a burst
+/// of changes is written once.
+const QUEUE_SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// Most files pushed to a remote at once; the rest wait for the next push.
+const MAX_PUSH_FILES: usize = 100;
+
+/// Bytes read, or buffered between producer and consumer, at a time when
+/// streaming content.
+const STREAM_BUFFER_LEN: usize = 256 * 1024;
+
+/// How long a new folder is left before its files are indexed. notify only
+/// starts watching it after reporting it, so files written in between would
+/// otherwise never produce an event.
+const FOLDER_SETTLE: Duration = Duration::from_millis(500);
+
 pub struct SyncEngine {
     pub p2p: Arc<P2pNode>,
     pub indexer: Arc<RwLock<VaultIndexer>>,
     pub watcher: Option<VaultWatcher>,
-    pub github: Option<Arc<GitHubStorage>>,
     pub status: Arc<RwLock<SyncStatus>>,
     pub vault_path: PathBuf,
     pub encryptor: Arc<Encryptor>,
+    /// Files written by `apply_remote_change`, so their watcher events aren't
+    /// mistaken for local edits.
+    applied: Mutex<HashMap<PathBuf, ([u8; 32], Instant)>>,
+    /// Deleted files not yet tombstoned: relative path -> (content hash, when).
+    pending_removals: Mutex<HashMap<String, ([u8; 32], Instant)>>,
+    /// Folders created or moved into the vault, waiting to be walked.
+    pending_folders: Mutex<HashMap<PathBuf, Instant>>,
+    /// Changed files waiting out their quiet period.
+    changes: Mutex<ChangeQueue>,
+    uploads: Arc<Semaphore>,
+    conflict_strategy: RwLock<ConflictStrategy>,
+    conflicts: RwLock<Vec<ConflictRecord>>,
+    ignore: Arc<RwLock<IgnoreRules>>,
+    /// This device's own ignore rules, layered over `.oversyncignore`.
+    local_ignore_path: PathBuf,
+    config_sync: RwLock<ConfigSyncSettings>,
+    config_sync_path: PathBuf,
+    keys: Mutex<KeyRing>,
+    key_params_path: PathBuf,
+    data_dir: PathBuf,
+    /// Remotes the vault is synced through besides peers.
+    remotes: RwLock<Vec<Arc<RemoteSync>>>,
+}
+
+/// A remote with what is waiting to be pushed to it.
+struct RemoteSync {
+    remote: Remote,
+    queue: Mutex<PushQueue>,
+    queue_path: PathBuf,
+    /// Wakes the task saving the queue, which waits for changes to settle.
+    queue_changed: Notify,
+    /// Last manifest version whose changes were all applied.
+    pulled: Mutex<Option<String>>,
+    /// Queued paths the remote holds an edit of that ours doesn't follow.
+    /// They are pulled despite being queued, so the two get settled.
+    contested: Mutex<HashSet<String>>,
+}
+
+impl RemoteSync {
+    /// A remote this device never pushed to has every file in `index`
+    /// queued, so it gets the whole vault rather than later edits only.
+    fn open(backend: Arc<dyn RemoteBackend>, data_dir: &Path, index: &VaultIndexer) -> Result<Self> {
+        let queue_path = data_dir.join(format!("{}{}", backend.name(), PUSH_QUEUE_SUFFIX));
+        let queue = PushQueue::load(&queue_path).unwrap_or_else(|| PushQueue {
+            pending: index
+                .metadata
+                .values()
+                .filter(|meta| !meta.is_deleted())
+                .map(|meta| (meta.path.clone(), RemoteChange::Upload))
+                .collect(),
+            pushing: BTreeMap::new(),
+        });
+        Ok(Self {
+            queue: Mutex::new(queue),
+            queue_path,
+            queue_changed: Notify::new(),
+            remote: Remote::new(backend),
+            pulled: Mutex::new(None),
+            contested: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn name(&self) -> &str {
+        self.remote.name()
+    }
+
+    /// Have the queue saved once changes to it settle for
+    /// `QUEUE_SAVE_DELAY`.
+    fn save_queue(&self) {
+        self.queue_changed.notify_one();
+    }
+
+    /// Write the queue through a temp file, so a crash mid-write leaves the
+    /// last one saved rather than a truncated one.
+    async fn write_queue(&self) -> Result<()> {
+        let bytes = serde_json::to_vec(&*self.queue.lock().await)?;
+        if let Some(parent) = self.queue_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut temp = self.queue_path.clone().into_os_string();
+        temp.push(TEMP_SUFFIX);
+        tokio::fs::write(&temp, bytes).await?;
+        tokio::fs::rename(&temp, &self.queue_path).await?;
+        Ok(())
+    }
+}
+
+/// What the next push to a remote does with a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RemoteChange {
+    Upload,
+    Delete,
+}
+
+/// Paths changed since the last push to a remote, by what to do with them.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PushQueue {
+    pending: BTreeMap<String, RemoteChange>,
+    /// Taken by the push in progress; saved along with `pending` until it
+    /// lands.
+    #[serde(default)]
+    pushing: BTreeMap<String, RemoteChange>,
+}
+
+impl PushQueue {
+    /// `None` when no queue was saved. One that can't be read is logged
+    /// and taken to be empty. Changes a push was cut short taking are
+    /// queued again.
+    fn load(path: &Path) -> Option<Self> {
+        let read = match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice::<Self>(&bytes).map_err(anyhow::Error::from),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => Err(e.into()),
+        };
+        let mut queue = read.unwrap_or_else(|e| {
+            eprintln!("Starting over with an empty push queue; {} can't be read: {}", path.display(), e);
+            Self::default()
+        });
+        for (path, change) in std::mem::take(&mut queue.pushing) {
+            queue.pending.entry(path).or_insert(change);
+        }
+        Some(queue)
+    }
 }
 
 impl SyncEngine {
     pub async fn new(
         vault_path: PathBuf,
-        p2p_data_dir: PathBuf,
-        encryption_key: [u8; 32],
+        data_dir: PathBuf,
+        encryption_key: KeyRing,
         github_config: Option<GithubConfig>,
+        encryption_mode: EncryptionMode,
     ) -> Result<Arc<Self>> {
-        let p2p = Arc::new(P2pNode::new(p2p_data_dir).await?);
-        let indexer = Arc::new(RwLock::new(VaultIndexer::new()));
-        let encryptor = Arc::new(Encryptor::new(&encryption_key));
-        
-        let github = if let Some(config) = github_config {
-            Some(Arc::new(GitHubStorage::new(config.token, config.owner, config.repo, config.branch, &encryption_key)?))
-        } else {
-            None
-        };
+        let local_ignore_path = data_dir.join(LOCAL_IGNORE_FILE);
+        let config_sync_path = data_dir.join(CONFIG_SYNC_FILE);
+        let config_sync = ConfigSyncSettings::load(&config_sync_path)?;
+        let rules = IgnoreRules::load(&vault_path, &config_sync.ignore_rules(), &local_ignore_path)?;
+        let mut index = VaultIndexer::open(&data_dir.join("index.redb"))?;
+        index.forget(|path| rules.is_ignored(Path::new(path), false))?;
+        let indexer = Arc::new(RwLock::new(index));
+        let ignore = Arc::new(RwLock::new(rules));
+        let p2p = Arc::new(P2pNode::new(data_dir.join("p2p_data"), indexer.clone(), ignore.clone()).await?);
+        indexer.write().await.set_device_id(p2p.node_id().await.to_string());
+        let encryptor = Arc::new(Encryptor::with_ring(&encryption_key, encryption_mode));
+
+        let mut remotes = Vec::new();
+        if let Some(config) = github_config {
+            let github: Arc<dyn RemoteBackend> = Arc::new(GitHubStorage::new(config, encryptor.clone())?);
+            remotes.push(Arc::new(RemoteSync::open(github, &data_dir, &*indexer.read().await)?));
+        }
 
         let status = Arc::new(RwLock::new(SyncStatus {
             is_syncing: false,
             last_sync: None,
             peers_connected: 0,
+            scan: None,
+            rotation: None,
+            remote_limits: BTreeMap::new(),
         }));
 
-        let (tx, mut rx) = mpsc::unbounded_channel();
-        let watcher = VaultWatcher::new(&vault_path, tx)?;
+        let (tx, mut rx) = mpsc::channel(EVENT_QUEUE_CAPACITY);
+        let watcher = VaultWatcher::new(&vault_path, tx.clone())?;
 
         let engine = Arc::new(Self {
             p2p,
             indexer,
             watcher: Some(watcher),
-            github,
             status,
             vault_path: vault_path.clone(),
             encryptor,
+            applied: Mutex::new(HashMap::new()),
+            pending_removals: Mutex::new(HashMap::new()),
+            pending_folders: Mutex::new(HashMap::new()),
+            changes: Mutex::new(ChangeQueue::default()),
+            uploads: Arc::new(Semaphore::new(MAX_CONCURRENT_UPLOADS)),
+            conflict_strategy: RwLock::new(ConflictStrategy::default()),
+            conflicts: RwLock::new(Vec::new()),
+            ignore,
+            local_ignore_path,
+            config_sync: RwLock::new(config_sync),
+            config_sync_path,
+            keys: Mutex::new(encryption_key),
+            key_params_path: data_dir.join(KEY_PARAMS_FILE),
+            data_dir,
+            remotes: RwLock::new(remotes.clone()),
         });
 
         let engine_clone = engine.clone();
         tokio::spawn(async move {
-            while let Some(event) = rx.recv().await {
-                if let Err(e) = engine_clone.handle_watcher_event(event).await {
+            loop {
+                match tokio::time::timeout(FLUSH_INTERVAL, rx.recv()).await {
+                    Ok(Some(event)) => {
+                        if let Err(e) = engine_clone.handle_watcher_event(event).await {
+                            eprintln!("Error handling watcher event: {}", e);
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(_) => {}
+                }
+                if let Err(e) = engine_clone.flush_pending().await {
                     eprintln!("Error handling watcher event: {}", e);
                 }
             }
         });
 
+        let engine_clone = engine.clone();
+        tokio::spawn(async move {
+            if let Err(e) = engine_clone.scan_vault(tx).await {
+                eprintln!("Startup scan failed: {}", e);
+            }
+            engine_clone.set_scan_progress(None).await;
+        });
+
         let engine_clone = engine.clone();
         let mut p2p_rx = engine.p2p.subscribe();
         tokio::spawn(async move {
@@ -74,21 +300,156 @@ impl SyncEngine {
             }
         });
 
+        for remote in remotes {
+            engine.spawn_remote_sync(remote);
+        }
+
+        let engine_clone = engine.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ANTI_ENTROPY_INTERVAL);
+            loop {
+                interval.tick().await;
+                engine_clone.p2p.sync_all().await;
+                if let Err(e) = engine_clone.collect_garbage().await {
+                    eprintln!("Tombstone collection failed: {}", e);
+                }
+            }
+        });
+
         Ok(engine)
     }
 
+    /// Walk the vault and queue a synthetic change for every file that
+    /// differs from the persisted index, so edits made while the app was
+    /// closed are picked up. Size and mtime are checked first; files are only
+    /// re-hashed when those differ. Removals are queued before creations so
+    /// files moved while the app was closed are detected as moves.
+    async fn scan_vault(&self, tx: mpsc::Sender<Event>) -> Result<()> {
+        use notify::event::{CreateKind, DataChange, EventKind, ModifyKind, RemoveKind};
+
+        let vault_path = self.vault_path.clone();
+        let rules = self.ignore.read().await.clone();
+        let files = tokio::task::spawn_blocking(move || list_vault_files(&vault_path, &vault_path, &rules)).await??;
+        let total = files.len();
+        self.set_scan_progress(Some(ScanProgress { scanned: 0, total })).await;
+
+        let mut seen = HashSet::new();
+        let mut changed = Vec::new();
+        for (scanned, (path, size, mtime)) in files.into_iter().enumerate() {
+            self.set_scan_progress(Some(ScanProgress { scanned: scanned + 1, total })).await;
+            // A file that can't be looked at is left for its next event
+            // rather than ending the scan.
+            let relative_path = match path.strip_prefix(&self.vault_path) {
+                Ok(relative) => relative.to_string_lossy().to_string(),
+                Err(e) => {
+                    eprintln!("Skipping {} in the scan: {}", path.display(), e);
+                    continue;
+                }
+            };
+            seen.insert(relative_path.clone());
+
+            let known = self
+                .indexer
+                .read()
+                .await
+                .get_metadata(&relative_path)
+                .map(|meta| (meta.size, meta.last_modified, meta.hash, meta.blob.is_some() && !meta.is_chunked()));
+
+            let kind = match known {
+                None => Some(EventKind::Create(CreateKind::File)),
+                // Stored as one blob before chunking; republish as chunks.
+                Some((.., true)) => Some(EventKind::Modify(ModifyKind::Data(DataChange::Content))),
+                Some((known_size, known_mtime, ..)) if known_size == size && known_mtime == mtime => None,
+                Some((_, _, known_hash, _)) => match hash_file(&path).await {
+                    Ok((hash, _)) if hash == known_hash => None,
+                    Ok(_) => Some(EventKind::Modify(ModifyKind::Data(DataChange::Content))),
+                    Err(e) => {
+                        eprintln!("Skipping {} in the scan: {}", path.display(), e);
+                        None
+                    }
+                },
+            };
+
+            if let Some(kind) = kind {
+                changed.push(Event::new(kind).add_path(path));
+            }
+        }
+
+        let removed: Vec<String> = self
+            .indexer
+            .read()
+            .await
+            .metadata
+            .values()
+            .filter(|meta| !meta.is_deleted() && !seen.contains(&meta.path))
+            .map(|meta| meta.path.clone())
+            .collect();
+        for relative_path in removed {
+            let event = Event::new(EventKind::Remove(RemoveKind::File))
+                .add_path(self.vault_path.join(relative_path));
+            tx.send(event).await?;
+        }
+        for event in changed {
+            tx.send(event).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn set_scan_progress(&self, scan: Option<ScanProgress>) {
+        let mut status = self.status.write().await;
+        status.is_syncing = scan.is_some();
+        status.scan = scan;
+    }
+
     async fn handle_watcher_event(&self, event: Event) -> Result<()> {
-        use notify::event::{EventKind, ModifyKind};
+        use notify::event::{EventKind, ModifyKind, RenameMode};
+
+        if event.paths.iter().any(|path| *path == self.vault_path.join(IGNORE_FILE)) {
+            self.reload_ignore_rules().await?;
+        }
 
         match event.kind {
-            EventKind::Modify(ModifyKind::Data(_)) | EventKind::Create(_) => {
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                let (from, to) = (&event.paths[0], &event.paths[1]);
+                self.changes.lock().await.cancel(from);
+                // Moving into or out of an ignored folder (such as Obsidian's
+                // `.trash/`) is a delete or a create as far as peers go.
+                match (self.is_ignored(from).await, self.is_ignored(to).await) {
+                    (false, false) => self.process_move(from, to).await?,
+                    (false, true) => self.queue_removal(from).await?,
+                    (true, false) => self.changes.lock().await.push(to.clone(), Instant::now()),
+                    (true, true) => {}
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) | EventKind::Remove(_) => {
                 for path in event.paths {
-                    self.process_file_change(path).await?;
+                    if self.is_ignored(&path).await {
+                        continue;
+                    }
+                    self.changes.lock().await.cancel(&path);
+                    self.queue_removal(&path).await?;
                 }
             }
-            EventKind::Remove(_) => {
+            EventKind::Modify(ModifyKind::Data(_))
+            | EventKind::Modify(ModifyKind::Name(_))
+            | EventKind::Create(_) => {
                 for path in event.paths {
-                    self.process_file_removal(path).await?;
+                    if is_temp_file(&path) || self.is_ignored(&path).await {
+                        continue;
+                    }
+                    match tokio::fs::metadata(&path).await {
+                        Ok(meta) if meta.is_dir() => {
+                            self.pending_folders.lock().await.insert(path, Instant::now());
+                        }
+                        Ok(_) => self.changes.lock().await.push(path, Instant::now()),
+                        // Platforms that can't pair rename events report each
+                        // side on its own; the old path no longer exists.
+                        Err(_) => {
+                            self.changes.lock().await.cancel(&path);
+                            self.queue_removal(&path).await?;
+                        }
+                    }
                 }
             }
             _ => {}
@@ -97,68 +458,1163 @@ impl SyncEngine {
         Ok(())
     }
 
+    /// Apply a rename reported with both paths. Renaming a folder moves
+    /// every indexed file under it.
+    async fn process_move(&self, from: &Path, to: &Path) -> Result<()> {
+        let from_relative = from.strip_prefix(&self.vault_path)?.to_string_lossy().to_string();
+        let to_relative = to.strip_prefix(&self.vault_path)?.to_string_lossy().to_string();
+
+        let mut moves = Vec::new();
+        {
+            let indexer = self.indexer.read().await;
+            if indexer.get_metadata(&from_relative).is_some_and(|meta| !meta.is_deleted()) {
+                moves.push((from_relative.clone(), to_relative.clone()));
+            } else if tokio::fs::metadata(to).await.is_ok_and(|meta| meta.is_dir()) {
+                let prefix = format!("{}{}", from_relative, std::path::MAIN_SEPARATOR);
+                for meta in indexer.metadata.values().filter(|meta| !meta.is_deleted()) {
+                    if let Some(rest) = meta.path.strip_prefix(&prefix) {
+                        let target = Path::new(&to_relative).join(rest).to_string_lossy().to_string();
+                        moves.push((meta.path.clone(), target));
+                    }
+                }
+            }
+        }
+
+        if moves.is_empty() {
+            // Not something we were tracking, or our own rename of a peer's move.
+            self.changes.lock().await.push(to.to_path_buf(), Instant::now());
+            return Ok(());
+        }
+        for (from, to) in moves {
+            self.record_move(&from, &to).await?;
+            // Picks up an edit made together with the rename.
+            self.changes.lock().await.push(self.vault_path.join(&to), Instant::now());
+        }
+
+        Ok(())
+    }
+
+    async fn record_move(&self, from: &str, to: &str) -> Result<()> {
+        let mut indexer = self.indexer.write().await;
+        indexer.move_file(from, to, Utc::now().timestamp() as u64)?;
+
+        // Queued before the tombstone can be collected.
+        self.queue_remotes(to, RemoteChange::Upload).await;
+        self.queue_remotes(from, RemoteChange::Delete).await;
+
+        Ok(())
+    }
+
+    /// Drop tombstones every device acknowledged, keeping those still queued
+    /// for a remote: the push records the deletion there from them.
+    async fn collect_garbage(&self) -> Result<Vec<String>> {
+        let mut indexer = self.indexer.write().await;
+        let mut pending = HashSet::new();
+        for remote in self.remotes.read().await.iter() {
+            let queue = remote.queue.lock().await;
+            pending.extend(queue.pending.keys().chain(queue.pushing.keys()).cloned());
+        }
+        indexer.collect_garbage(&pending, Utc::now().timestamp() as u64)
+    }
+
+    /// Hold a deletion back for `MOVE_WINDOW` (plus the quiet period the
+    /// matching creation waits out) so it can turn into a move.
+    async fn queue_removal(&self, path: &Path) -> Result<()> {
+        let relative_path = path.strip_prefix(&self.vault_path)?
+            .to_string_lossy()
+            .to_string();
+
+        // A removed folder takes every file indexed under it along.
+        let prefix = format!("{}{}", relative_path, std::path::MAIN_SEPARATOR);
+        let indexer = self.indexer.read().await;
+        let mut pending = self.pending_removals.lock().await;
+        for meta in indexer.metadata.values().filter(|meta| !meta.is_deleted()) {
+            if meta.path == relative_path || meta.path.starts_with(&prefix) {
+                pending.insert(meta.path.clone(), (meta.hash, Instant::now()));
+            }
+        }
+        Ok(())
+    }
+
+    async fn is_ignored(&self, path: &Path) -> bool {
+        let Ok(relative_path) = path.strip_prefix(&self.vault_path) else {
+            return true;
+        };
+        self.ignore.read().await.is_ignored(relative_path, path.is_dir())
+    }
+
+    /// Re-read `.oversyncignore` and the device overrides. Newly ignored
+    /// paths are dropped from the index (peers keep them); files that are
+    /// no longer ignored are queued as new.
+    pub async fn reload_ignore_rules(&self) -> Result<()> {
+        let config_rules = self.config_sync.read().await.ignore_rules();
+        let rules = IgnoreRules::load(&self.vault_path, &config_rules, &self.local_ignore_path)?;
+        self.indexer
+            .write()
+            .await
+            .forget(|path| rules.is_ignored(Path::new(path), false))?;
+
+        let vault_path = self.vault_path.clone();
+        let walk_rules = rules.clone();
+        let files = tokio::task::spawn_blocking(move || list_vault_files(&vault_path, &vault_path, &walk_rules)).await??;
+        *self.ignore.write().await = rules;
+
+        let indexer = self.indexer.read().await;
+        let mut changes = self.changes.lock().await;
+        for (path, _, _) in files {
+            let relative_path = path.strip_prefix(&self.vault_path)?.to_string_lossy().to_string();
+            if indexer.get_metadata(&relative_path).is_none_or(|meta| meta.is_deleted()) {
+                changes.push(path, Instant::now());
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn config_sync(&self) -> ConfigSyncSettings {
+        self.config_sync.read().await.clone()
+    }
+
+    /// Change which `.obsidian/` categories are synced. Applies to both P2P
+    /// and GitHub, as both only see what the ignore rules let through.
+    pub async fn set_config_sync(&self, settings: ConfigSyncSettings) -> Result<()> {
+        settings.save(&self.config_sync_path)?;
+        *self.config_sync.write().await = settings;
+        self.reload_ignore_rules().await
+    }
+
+    /// This device's ignore rules, one gitignore line each.
+    pub async fn ignore_overrides(&self) -> Result<Vec<String>> {
+        match tokio::fs::read_to_string(&self.local_ignore_path).await {
+            Ok(content) => Ok(content.lines().map(str::to_string).collect()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn set_ignore_overrides(&self, lines: Vec<String>) -> Result<()> {
+        // Validate before anything is written.
+        IgnoreRules::from_rules(&self.vault_path, &lines)?;
+        if let Some(parent) = self.local_ignore_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&self.local_ignore_path, lines.join("\n")).await?;
+        self.reload_ignore_rules().await
+    }
+
+    /// Queue files under settled new folders, process changes that have
+    /// been quiet long enough, and tombstone deletions that weren't claimed
+    /// by a move in time.
+    async fn flush_pending(&self) -> Result<()> {
+        let settled: Vec<PathBuf> = {
+            let mut pending = self.pending_folders.lock().await;
+            let settled = pending
+                .iter()
+                .filter(|(_, at)| at.elapsed() >= FOLDER_SETTLE)
+                .map(|(path, _)| path.clone())
+                .collect::<Vec<_>>();
+            for path in &settled {
+                pending.remove(path);
+            }
+            settled
+        };
+        for dir in settled {
+            let vault_path = self.vault_path.clone();
+            let rules = self.ignore.read().await.clone();
+            let files = tokio::task::spawn_blocking(move || list_vault_files(&vault_path, &dir, &rules)).await??;
+            let mut changes = self.changes.lock().await;
+            for (file, _, _) in files {
+                changes.push(file, Instant::now());
+            }
+        }
+
+        let (settled, move_window) = {
+            let mut changes = self.changes.lock().await;
+            (changes.take_settled(Instant::now()), MOVE_WINDOW + changes.quiet_period())
+        };
+        for path in settled {
+            if let Err(e) = self.process_file_change(path.clone()).await {
+                eprintln!("Failed to process {}: {}", path.display(), e);
+            }
+        }
+
+        let expired: Vec<String> = {
+            let mut pending = self.pending_removals.lock().await;
+            let expired = pending
+                .iter()
+                .filter(|(_, (_, at))| at.elapsed() >= move_window)
+                .map(|(path, _)| path.clone())
+                .collect::<Vec<_>>();
+            for path in &expired {
+                pending.remove(path);
+            }
+            expired
+        };
+
+        for relative_path in expired {
+            let path = self.vault_path.join(&relative_path);
+            // Replaced in place, e.g. by an editor's save-via-rename.
+            if tokio::fs::try_exists(&path).await? {
+                continue;
+            }
+            self.process_file_removal(path).await?;
+        }
+        Ok(())
+    }
+
+    /// A pending deletion of a file with this content, if there is one.
+    async fn claim_removal(&self, hash: &[u8; 32]) -> Option<String> {
+        let mut pending = self.pending_removals.lock().await;
+        let from = pending
+            .iter()
+            .find(|(_, (pending_hash, _))| pending_hash == hash)
+            .map(|(path, _)| path.clone())?;
+        pending.remove(&from);
+        Some(from)
+    }
+
     async fn process_file_change(&self, path: PathBuf) -> Result<()> {
         let relative_path = path.strip_prefix(&self.vault_path)?
             .to_string_lossy()
             .to_string();
 
-        let content = tokio::fs::read(&path).await?;
-        let last_modified = Utc::now().timestamp() as u64;
+        let file_meta = tokio::fs::metadata(&path).await?;
+        if file_meta.is_dir() {
+            return Ok(());
+        }
+
+        // Hashed, and later chunked, straight from disk, so a large file is
+        // never held in memory.
+        let (content_hash, size) = hash_file(&path).await?;
+        let last_modified = mtime_secs(&file_meta);
 
-        // 1. Update Indexer
+        if self.is_echo(&path, &content_hash).await {
+            return Ok(());
+        }
+
+        // A new path with the content of a file deleted moments ago is a move.
+        let is_new = self
+            .indexer
+            .read()
+            .await
+            .get_metadata(&relative_path)
+            .is_none_or(|meta| meta.is_deleted());
+        if is_new {
+            if let Some(from) = self.claim_removal(&content_hash).await {
+                return self.record_move(&from, &relative_path).await;
+            }
+        }
+
+        // 1. Update Indexer. Unchanged content only refreshes the mtime;
+        // there is nothing new to seal or upload.
         let mut indexer = self.indexer.write().await;
-        indexer.update_file(relative_path.clone(), &content, last_modified)?;
-        let _root_hash = indexer.root_hash();
+        let unchanged = indexer
+            .get_metadata(&relative_path)
+            .is_some_and(|meta| !meta.is_deleted() && meta.hash == content_hash && meta.is_chunked());
+        indexer.record_file(relative_path.clone(), content_hash, size, last_modified)?;
         drop(indexer);
+        if unchanged {
+            return Ok(());
+        }
+
+        // 2. Encrypt and add to Iroh Blobs so peers can fetch it
+        self.publish_blob(&relative_path, content_hash, ContentSource::File(path.clone()))?;
 
-        // 2. Encrypt file
-        let (ciphertext, _nonce) = self.encryptor.encrypt(&content)?;
+        // 3. Queue to remotes
+        self.queue_remotes(&relative_path, RemoteChange::Upload).await;
 
-        // 3. Add to Iroh Blobs and notify peers
+        let mut status = self.status.write().await;
+        status.last_sync = Some(Utc::now());
+        
+        Ok(())
+    }
+
+    /// Chunk and seal the content from `source` into the blob store in the
+    /// background and record the chunks against `relative_path` once they
+    /// have been added. Chunks already stored for any file are reused rather
+    /// than sealed again. Content that no longer hashes to `content_hash`
+    /// isn't recorded; the change that altered it is on its way.
+    fn publish_blob(&self, relative_path: &str, content_hash: [u8; 32], source: ContentSource) -> Result<()> {
         let p2p = self.p2p.clone();
-        let ciphertext_clone = ciphertext.clone();
+        let indexer = self.indexer.clone();
+        let encryptor = self.encryptor.clone();
+        let rel_path_clone = relative_path.to_string();
+        self.spawn_upload(async move {
+            let stored = async { store_chunks(&p2p, &indexer, &encryptor, source.reader().await?).await }.await;
+            match stored {
+                Ok((blob, chunks, hash)) if hash == content_hash => {
+                    let mut indexer = indexer.write().await;
+                    if let Err(e) = indexer.set_blob(&rel_path_clone, content_hash, blob, &chunks) {
+                        eprintln!("Failed to record blob for {}: {}", rel_path_clone, e);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Failed to add blob to Iroh: {}", e),
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Decrypt the content behind a file's hash sequence into `writer`, a
+    /// chunk at a time, and return its hash. Every chunk must already be in
+    /// the local store.
+    async fn write_content<W: AsyncWrite + Unpin>(&self, blob: [u8; 32], writer: &mut W) -> Result<[u8; 32]> {
+        let mut hasher = blake3::Hasher::new();
+        let mut piece = Vec::new();
+        for chunk in self.p2p.read_hash_seq(blob.into()).await? {
+            piece.clear();
+            let mut sealed = self.p2p.blob_reader(chunk).await?;
+            self.encryptor.open_stream(&mut sealed, &mut piece, Binding::NONE).await?;
+            hasher.update(&piece);
+            writer.write_all(&piece).await?;
+        }
+        writer.flush().await?;
+        Ok(hasher.finalize().into())
+    }
+
+    /// Reassemble and decrypt the content behind a file's hash sequence.
+    async fn read_content(&self, blob: [u8; 32]) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.write_content(blob, &mut content).await?;
+        Ok(content)
+    }
+
+    /// Run `consume` on a reader over the decrypted content behind `blob`,
+    /// fed a chunk at a time as it is read.
+    async fn pipe_content<T, F, Fut>(&self, blob: [u8; 32], consume: F) -> Result<T>
+    where
+        F: FnOnce(DuplexStream) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        pipe(|mut writer| async move { self.write_content(blob, &mut writer).await.map(drop) }, consume).await
+    }
+
+    /// Run a blob import or remote request in the background, at most
+    /// `MAX_CONCURRENT_UPLOADS` at a time.
+    fn spawn_upload<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let uploads = self.uploads.clone();
         tokio::spawn(async move {
-            if let Err(e) = p2p.add_blob(ciphertext_clone).await {
-                eprintln!("Failed to add blob to Iroh: {}", e);
+            let Ok(_permit) = uploads.acquire_owned().await else {
+                return;
+            };
+            task.await;
+        });
+    }
+
+    /// Sync through `backend` as well as the remotes already configured.
+    /// Its name must differ from theirs.
+    pub async fn add_remote(self: &Arc<Self>, backend: Arc<dyn RemoteBackend>) -> Result<()> {
+        let remote = Arc::new(RemoteSync::open(backend, &self.data_dir, &*self.indexer.read().await)?);
+        let mut remotes = self.remotes.write().await;
+        if remotes.iter().any(|configured| configured.name() == remote.name()) {
+            return Err(anyhow!("a remote named {} is already configured", remote.name()));
+        }
+        remotes.push(remote.clone());
+        drop(remotes);
+        self.spawn_remote_sync(remote);
+        Ok(())
+    }
+
+    /// Every `REMOTE_SYNC_INTERVAL`, push what is queued for `remote` and
+    /// pull what changed there.
+    fn spawn_remote_sync(self: &Arc<Self>, remote: Arc<RemoteSync>) {
+        let saver = remote.clone();
+        tokio::spawn(async move {
+            loop {
+                saver.queue_changed.notified().await;
+                tokio::time::sleep(QUEUE_SAVE_DELAY).await;
+                if let Err(e) = saver.write_queue().await {
+                    eprintln!("Failed to save the {} push queue: {}", saver.name(), e);
+                }
             }
         });
 
-        // 4. Queue to GitHub
-        if let Some(github) = &self.github {
-            let github = github.clone();
-            let rel_path_clone = relative_path.clone();
-            tokio::spawn(async move {
-                if let Err(e) = github.upload_file(&rel_path_clone, &content).await {
-                    eprintln!("GitHub upload failed for {}: {}", rel_path_clone, e);
-                } else {
-                    println!("GitHub upload successful for {}", rel_path_clone);
+        let engine = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REMOTE_SYNC_INTERVAL);
+            let mut published = false;
+            loop {
+                interval.tick().await;
+                // Writes would only wait for the limit to pass; reads may
+                // not be held back by it.
+                if remote.remote.backend.limited_until().is_none() {
+                    if !published {
+                        match engine.publish_key_params(&remote).await {
+                            Ok(()) => published = true,
+                            Err(e) => eprintln!("Publishing key parameters to {} failed: {}", remote.name(), e),
+                        }
+                    }
+                    if let Err(e) = engine.push_to_remote(&remote).await {
+                        eprintln!("{} push failed: {}", remote.name(), e);
+                    }
                 }
-            });
+                if let Err(e) = engine.pull_from_remote(&remote).await {
+                    eprintln!("{} pull failed: {}", remote.name(), e);
+                }
+            }
+        });
+    }
+
+    /// Publish this device's key parameters on `remote`, so devices joining
+    /// through it derive the same key, unless it has them or a later
+    /// epoch's already.
+    async fn publish_key_params(&self, remote: &RemoteSync) -> Result<()> {
+        let Some(params) = KeyParams::load(&self.key_params_path)? else {
+            return Ok(());
+        };
+        match remote.remote.backend.key_params().await? {
+            Some(published) if published.epoch >= params.epoch => {
+                if published.check != params.check {
+                    eprintln!(
+                        "{} has key parameters for another key; devices syncing through it can't read each other's content",
+                        remote.name()
+                    );
+                }
+                Ok(())
+            }
+            _ => remote.remote.backend.publish_key_params(&params).await,
+        }
+    }
+
+    /// Have the next push to every remote upload or delete `relative_path`;
+    /// the latest change to a path wins.
+    async fn queue_remotes(&self, relative_path: &str, change: RemoteChange) {
+        for remote in self.remotes.read().await.iter() {
+            let mut queue = remote.queue.lock().await;
+            queue.pending.insert(relative_path.to_string(), change);
+            remote.save_queue();
         }
+    }
 
-        let mut status = self.status.write().await;
-        status.last_sync = Some(Utc::now());
-        
+    /// Have every remote but `from` take the change to `relative_path` just
+    /// pulled from it, so a device syncing through several carries edits
+    /// between them.
+    async fn relay(&self, from: &RemoteSync, relative_path: &str) {
+        let deleted = match self.indexer.read().await.get_metadata(relative_path) {
+            Some(meta) => meta.is_deleted(),
+            None => return,
+        };
+        let change = if deleted { RemoteChange::Delete } else { RemoteChange::Upload };
+        for remote in self.remotes.read().await.iter().filter(|remote| remote.name() != from.name()) {
+            let mut queue = remote.queue.lock().await;
+            queue.pending.insert(relative_path.to_string(), change);
+            remote.save_queue();
+        }
+    }
+
+    /// Push up to `MAX_PUSH_FILES` changes queued for `remote` at once,
+    /// reading each upload from the vault as it is now. On failure the
+    /// changes are queued again, behind any made meanwhile, as are those
+    /// left out for an edit there ours doesn't follow; the next pull then
+    /// looks at them again.
+    async fn push_to_remote(&self, remote: &RemoteSync) -> Result<()> {
+        let batch: Vec<(String, RemoteChange)> = {
+            let mut queue = remote.queue.lock().await;
+            let paths: Vec<String> = queue.pending.keys().take(MAX_PUSH_FILES).cloned().collect();
+            let batch: Vec<_> = paths
+                .into_iter()
+                .filter_map(|path| queue.pending.remove_entry(&path))
+                .collect();
+            queue.pushing = batch.iter().cloned().collect();
+            batch
+        };
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let _permit = self.uploads.acquire().await?;
+        let pushed = async {
+            let mut uploads = Vec::new();
+            let mut deletions = Vec::new();
+            for (path, change) in &batch {
+                let meta = self.indexer.read().await.get_metadata(path).cloned();
+                match (change, meta) {
+                    (RemoteChange::Upload, Some(meta)) if !meta.is_deleted() => {
+                        match tokio::fs::File::open(self.vault_path.join(path)).await {
+                            Ok(file) => uploads.push((meta, file)),
+                            // Gone since; its removal is queued once it is processed.
+                            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                    (RemoteChange::Delete, Some(meta)) if meta.is_deleted() => deletions.push(meta),
+                    // Superseded since by a change that is queued itself.
+                    _ => {}
+                }
+            }
+            remote.remote.push(uploads, deletions).await
+        }
+        .await;
+
+        let mut queue = remote.queue.lock().await;
+        let mut pushing = std::mem::take(&mut queue.pushing);
+        if let Ok(contested) = &pushed {
+            pushing.retain(|path, _| contested.contains(path));
+            if !contested.is_empty() {
+                remote.contested.lock().await.extend(contested.iter().cloned());
+                *remote.pulled.lock().await = None;
+            }
+        }
+        for (path, change) in pushing {
+            queue.pending.entry(path).or_insert(change);
+        }
+        remote.save_queue();
+        pushed.map(drop)
+    }
+
+    /// Apply what other devices pushed to `remote` since the last pull:
+    /// every file whose content or deletion there differs from ours, settled
+    /// by version like a peer's change. Paths with local changes not pushed
+    /// yet are left alone; the push settles them. What changes here is
+    /// queued for the other remotes.
+    async fn pull_from_remote(&self, remote: &RemoteSync) -> Result<()> {
+        let (version, index) = remote.remote.backend.list().await?;
+        if remote.pulled.lock().await.as_deref() == Some(version.as_str()) {
+            return Ok(());
+        }
+
+        let mut applied = true;
+        for (path, entry) in &index.files {
+            let before = self.indexer.read().await.get_metadata(path).cloned();
+            match self.pull_file(remote, path, entry).await {
+                Ok(()) => {
+                    remote.contested.lock().await.remove(path);
+                    if self.indexer.read().await.get_metadata(path) != before.as_ref() {
+                        self.relay(remote, path).await;
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Failed to pull {} from {}: {}", path, remote.name(), e);
+                    applied = false;
+                }
+            }
+        }
+        // Retried on the next pull until every file has been applied.
+        if applied {
+            *remote.pulled.lock().await = Some(version);
+        }
         Ok(())
     }
 
+    async fn pull_file(&self, remote: &RemoteSync, path: &str, entry: &RemoteEntry) -> Result<()> {
+        let queued = remote.queue.lock().await.pending.contains_key(path);
+        if (queued && !remote.contested.lock().await.contains(path)) || self.is_ignored(&self.vault_path.join(path)).await {
+            return Ok(());
+        }
+        let local = self.indexer.read().await.get_metadata(path).cloned();
+        if local.as_ref().is_some_and(|local| local.hash == entry.hash) {
+            return self.adopt_version(path, entry.hash, &entry.version).await;
+        }
+        if entry.tombstone.is_some() && local.as_ref().is_none_or(|local| local.is_deleted()) {
+            return Ok(());
+        }
+        // Pushed before versions were recorded, so nothing says which copy
+        // is newer; ours is pushed over it rather than lost.
+        if let Some(local) = local.as_ref().filter(|_| entry.version == VersionVector::default()) {
+            let change = if local.is_deleted() { RemoteChange::Delete } else { RemoteChange::Upload };
+            let mut queue = remote.queue.lock().await;
+            queue.pending.entry(path.to_string()).or_insert(change);
+            remote.save_queue();
+            return Ok(());
+        }
+        // An edit not indexed yet, such as one made while the app was
+        // closed, is settled against this one once the scan picks it up
+        // rather than lost; until then the pull is retried.
+        if let Some(local) = local.as_ref().filter(|local| !local.is_deleted()) {
+            match hash_file(&self.vault_path.join(path)).await {
+                Ok((hash, _)) if hash != local.hash => return Err(anyhow!("{} has local edits not indexed yet", path)),
+                _ => {}
+            }
+        }
+
+        if let Some(tombstone) = &entry.tombstone {
+            let meta = FileMetadata {
+                path: path.to_string(),
+                size: 0,
+                hash: entry.hash,
+                last_modified: tombstone.deleted_at,
+                blob: None,
+                chunks: Vec::new(),
+                version: entry.version.clone(),
+                history: Vec::new(),
+                tombstone: Some(tombstone.clone()),
+                moved_from: None,
+            };
+            return self.apply_remote_change(&meta, remote.name()).await;
+        }
+
+        let (blob, chunks, hash) = {
+            let _permit = self.uploads.acquire().await?;
+            pipe(
+                |mut writer| async move { remote.remote.backend.get(entry, &mut writer).await.map(drop) },
+                |reader| store_chunks(&self.p2p, &self.indexer, &self.encryptor, reader),
+            )
+            .await?
+        };
+        if hash != entry.hash {
+            return Err(anyhow!("content hash mismatch for {}", path));
+        }
+
+        let version = entry.version.clone();
+        let history = match &local {
+            // Only a copy it follows shares its history.
+            Some(local) if local.version.compare(&version) == Causality::Before => {
+                local.revisions().take(HISTORY_LEN).collect()
+            }
+            _ => Vec::new(),
+        };
+        let meta = FileMetadata {
+            path: path.to_string(),
+            size: entry.size,
+            hash,
+            last_modified: if entry.modified > 0 { entry.modified } else { Utc::now().timestamp() as u64 },
+            blob: Some(blob),
+            chunks,
+            version,
+            history,
+            tombstone: None,
+            moved_from: None,
+        };
+        self.apply_remote_change(&meta, remote.name()).await
+    }
+
     async fn process_file_removal(&self, path: PathBuf) -> Result<()> {
         let relative_path = path.strip_prefix(&self.vault_path)?
             .to_string_lossy()
             .to_string();
 
         let mut indexer = self.indexer.write().await;
-        indexer.remove_file(&relative_path)?;
-        
+        if indexer.get_metadata(&relative_path).is_none_or(|meta| meta.is_deleted()) {
+            // Unknown, or the removal was applied from a peer's tombstone.
+            return Ok(());
+        }
+        indexer.remove_file(&relative_path, Utc::now().timestamp() as u64)?;
+        // Queued before the tombstone can be collected.
+        self.queue_remotes(&relative_path, RemoteChange::Delete).await;
+        drop(indexer);
+
         let mut status = self.status.write().await;
         status.last_sync = Some(Utc::now());
 
         Ok(())
     }
 
+    /// Write a change received from a peer into the vault.
+    ///
+    /// The blob must already be in the local iroh store. Changes the local
+    /// version already includes are skipped, and edits concurrent with a
+    /// local one are settled by the configured `ConflictStrategy`.
+    pub async fn apply_remote_change(&self, meta: &FileMetadata, peer: &str) -> Result<()> {
+        let local = self.indexer.read().await.get_metadata(&meta.path).cloned();
+        if local.as_ref().is_some_and(|local| local.hash == meta.hash) {
+            return self.adopt_version(&meta.path, meta.hash, &meta.version).await;
+        }
+        if meta.is_deleted() {
+            return self.apply_remote_deletion(local, meta).await;
+        }
+
+        let blob = meta.blob.ok_or_else(|| anyhow!("no blob for {}", meta.path))?;
+        let causality = match &local {
+            Some(local) => local.version.compare(&meta.version),
+            None => Causality::Before,
+        };
+        if causality == Causality::After {
+            return Ok(());
+        }
+
+        match local {
+            // An edit concurrent with a delete wins, so the file comes back.
+            Some(local) if local.is_deleted() => {
+                let mut winner = meta.clone();
+                winner.version = local.version.merged(&meta.version);
+                self.write_remote_content(Some(&local), winner, blob).await
+            }
+            // Equal vectors with different content only happen for entries
+            // indexed before version vectors existed; treat them as concurrent.
+            Some(local) if causality != Causality::Before => self.resolve_conflict(&local, meta, blob, peer).await,
+            _ => self.write_remote_content(local.as_ref(), meta.clone(), blob).await,
+        }
+    }
+
+    /// Write the content behind `blob` into the vault and record `entry`
+    /// for it, provided our entry is still `local`. A local edit made since,
+    /// indexed or not, is left alone and the change fails, to be settled
+    /// against the edit when it is offered again.
+    async fn write_remote_content(&self, local: Option<&FileMetadata>, entry: FileMetadata, blob: [u8; 32]) -> Result<()> {
+        let on_disk = hash_file(&self.vault_path.join(&entry.path)).await.ok().map(|(hash, _)| hash);
+        let indexed = local.filter(|local| !local.is_deleted()).map(|local| local.hash);
+        if on_disk.is_some() && on_disk != indexed && on_disk != Some(entry.hash) {
+            return Err(anyhow!("{} has local edits not indexed yet", entry.path));
+        }
+        let (file, temp, target) = self.stage_vault_content(&entry.path, blob, entry.hash).await?;
+
+        let mut indexer = self.indexer.write().await;
+        // The blob of a local edit may have been recorded since; only a new
+        // edit or deletion counts.
+        let current = indexer.get_metadata(&entry.path).map(|meta| (meta.hash, &meta.version));
+        if current != local.map(|local| (local.hash, &local.version)) {
+            drop(file);
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(anyhow!("{} changed locally while it was being applied", entry.path));
+        }
+        finish_vault_write(file, &temp, &target, entry.last_modified).await?;
+        indexer.apply_remote(entry)?;
+        Ok(())
+    }
+
+    /// Fold `version`, of a copy with the same content, into our entry for
+    /// `path`, so the same edit made on two devices isn't taken for
+    /// concurrent ones once either edits again.
+    async fn adopt_version(&self, path: &str, hash: [u8; 32], version: &VersionVector) -> Result<()> {
+        let mut indexer = self.indexer.write().await;
+        let Some(mut local) = indexer.get_metadata(path).filter(|local| local.hash == hash).cloned() else {
+            return Ok(());
+        };
+        let merged = local.version.merged(version);
+        if merged != local.version {
+            local.version = merged;
+            indexer.apply_remote(local)?;
+        }
+        Ok(())
+    }
+
+    /// Replay a peer's move by renaming our copy of the source, so nothing
+    /// is downloaded and the note never disappears from the vault. Returns
+    /// `false` when the move can't be replayed as a plain rename.
+    async fn apply_remote_move(&self, entry: &FileMetadata, source: &FileMetadata) -> Result<bool> {
+        let mut indexer = self.indexer.write().await;
+        if !indexer.can_rename(entry, source) {
+            return Ok(false);
+        }
+
+        let mut moved = entry.clone();
+        if let Some(local) = indexer.get_metadata(&source.path) {
+            // Our blob for this content is the one we are sure to have.
+            moved.blob = local.blob.or(entry.blob);
+        }
+        if let Some(target) = indexer.get_metadata(&entry.path) {
+            moved.version.merge(&target.version);
+        }
+        indexer.apply_remote(moved)?;
+        indexer.apply_remote(source.clone())?;
+        drop(indexer);
+
+        self.rename_vault_file(&source.path, &entry.path, entry.hash).await?;
+        Ok(true)
+    }
+
+    /// Apply a peer's tombstone. The file is only removed when the deletion
+    /// happened after our last edit; a concurrent local edit wins and is
+    /// re-versioned to supersede the tombstone.
+    async fn apply_remote_deletion(&self, local: Option<FileMetadata>, meta: &FileMetadata) -> Result<()> {
+        let mut indexer = self.indexer.write().await;
+        match local {
+            // Already collected here; nothing to delete or acknowledge.
+            None if indexer.fully_acked(meta, Utc::now().timestamp() as u64) => {}
+            None => {
+                indexer.apply_remote(meta.clone())?;
+            }
+            Some(local) if local.is_deleted() => {
+                indexer.apply_remote(meta.clone())?;
+            }
+            Some(local) => match local.version.compare(&meta.version) {
+                Causality::Before => {
+                    indexer.apply_remote(meta.clone())?;
+                    drop(indexer);
+                    self.remove_vault_file(&meta.path).await?;
+                }
+                Causality::After => {}
+                Causality::Equal | Causality::Concurrent => {
+                    indexer.supersede(&local.path, &meta.version)?;
+                    drop(indexer);
+                    // Remotes still hold the tombstone.
+                    self.queue_remotes(&local.path, RemoteChange::Upload).await;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Settle `remote`'s edit, behind `blob`, against our concurrent
+    /// `local` one. Only notes are read into memory, to be merged; anything
+    /// else is streamed into the vault if it is kept.
+    async fn resolve_conflict(
+        &self,
+        local: &FileMetadata,
+        remote: &FileMetadata,
+        blob: [u8; 32],
+        peer: &str,
+    ) -> Result<()> {
+        if is_markdown(&remote.path) {
+            let content = self.read_content(blob).await?;
+            if <[u8; 32]>::from(blake3::hash(&content)) != remote.hash {
+                return Err(anyhow!("content hash mismatch for {}", remote.path));
+            }
+            match self.try_merge(local, remote, &content, peer).await {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(e) => eprintln!("Could not merge {}: {}", remote.path, e),
+            }
+        }
+
+        let strategy = *self.conflict_strategy.read().await;
+        let remote_wins = match strategy {
+            ConflictStrategy::PreferRemote => true,
+            ConflictStrategy::PreferLocal | ConflictStrategy::KeepBoth => false,
+            // Ties are broken by hash so both devices pick the same winner.
+            ConflictStrategy::LastWriterWins => {
+                (remote.last_modified, remote.hash) > (local.last_modified, local.hash)
+            }
+        };
+
+        let mut conflict_copy = None;
+        if remote_wins {
+            let mut winner = remote.clone();
+            winner.version = local.version.merged(&remote.version);
+            let (file, temp, target) = self.stage_vault_content(&remote.path, blob, remote.hash).await?;
+            finish_vault_write(file, &temp, &target, remote.last_modified).await?;
+            self.indexer.write().await.apply_remote(winner)?;
+        } else {
+            if strategy == ConflictStrategy::KeepBoth {
+                let device: String = peer.chars().take(8).collect();
+                let copy_path = conflict_copy_path(&remote.path, &device);
+                let (file, temp, target) = self.stage_vault_content(&copy_path, blob, remote.hash).await?;
+                finish_vault_write(file, &temp, &target, remote.last_modified).await?;
+                conflict_copy = Some(copy_path);
+            }
+
+            let mut indexer = self.indexer.write().await;
+            if let Some(copy_path) = &conflict_copy {
+                indexer.record_file(copy_path.clone(), remote.hash, remote.size, remote.last_modified)?;
+                indexer.set_blob(copy_path, remote.hash, blob, &remote.chunks)?;
+            }
+            indexer.supersede(&local.path, &remote.version)?;
+            drop(indexer);
+
+            // Remotes hold the losing edit, or never saw the copy.
+            self.queue_remotes(&local.path, RemoteChange::Upload).await;
+            if let Some(copy_path) = &conflict_copy {
+                self.queue_remotes(copy_path, RemoteChange::Upload).await;
+            }
+        }
+
+        self.conflicts.write().await.push(ConflictRecord {
+            path: remote.path.clone(),
+            peer: peer.to_string(),
+            detected_at: Utc::now(),
+            strategy,
+            conflict_copy,
+        });
+
+        Ok(())
+    }
+
+    /// Three-way merge a concurrent edit of a note against the newest
+    /// revision both sides have seen. Returns `false` when there is no
+    /// common ancestor or the edits overlap, leaving the note untouched.
+    async fn try_merge(
+        &self,
+        local: &FileMetadata,
+        remote: &FileMetadata,
+        content: &[u8],
+        peer: &str,
+    ) -> Result<bool> {
+        let Some(ancestor) = local
+            .history
+            .iter()
+            .find(|revision| remote.revisions().any(|theirs| theirs.hash == revision.hash))
+        else {
+            return Ok(false);
+        };
+
+        // Prefer our own copy of the ancestor; otherwise fetch the peer's.
+        let blob = match ancestor.blob {
+            Some(blob) => blob,
+            None => {
+                let Some(blob) = remote
+                    .revisions()
+                    .find(|theirs| theirs.hash == ancestor.hash)
+                    .and_then(|theirs| theirs.blob)
+                else {
+                    return Ok(false);
+                };
+                self.p2p.sync_content(peer.parse()?, blob.into()).await?;
+                blob
+            }
+        };
+        let base = self.read_content(blob).await?;
+        if <[u8; 32]>::from(blake3::hash(&base)) != ancestor.hash {
+            return Err(anyhow!("ancestor hash mismatch for {}", remote.path));
+        }
+
+        let ours = tokio::fs::read(self.vault_path.join(&local.path)).await?;
+        if <[u8; 32]>::from(blake3::hash(&ours)) != local.hash {
+            // The note changed since it was indexed; the watcher will catch up.
+            return Ok(false);
+        }
+
+        let (Ok(base), Ok(ours), Ok(theirs)) = (
+            String::from_utf8(base),
+            String::from_utf8(ours),
+            std::str::from_utf8(content),
+        ) else {
+            return Ok(false);
+        };
+
+        // Both devices merge the same pair, so order the sides by hash to
+        // make them produce identical text.
+        let merged = if local.hash < remote.hash {
+            merge_markdown(&base, &ours, theirs)
+        } else {
+            merge_markdown(&base, theirs, &ours)
+        };
+        let Some(merged) = merged else {
+            return Ok(false);
+        };
+
+        let merged = merged.into_bytes();
+        let merged_hash: [u8; 32] = blake3::hash(&merged).into();
+        let last_modified = local.last_modified.max(remote.last_modified);
+        self.write_vault_file(&local.path, &merged, last_modified).await?;
+        self.indexer
+            .write()
+            .await
+            .record_merge(&local.path, &merged, last_modified, remote)?;
+        self.publish_blob(&local.path, merged_hash, ContentSource::Bytes(merged))?;
+        self.queue_remotes(&local.path, RemoteChange::Upload).await;
+
+        Ok(true)
+    }
+
+    /// Atomically write `content` to `relative_path` inside the vault.
+    ///
+    /// The file is written to a temp file and renamed into place so Obsidian
+    /// never sees a partial note, and the watcher event the rename triggers
+    /// is suppressed.
+    async fn write_vault_file(&self, relative_path: &str, content: &[u8], last_modified: u64) -> Result<()> {
+        let (mut file, temp, target) = self.create_vault_temp(relative_path, blake3::hash(content).into()).await?;
+        file.write_all(content).await?;
+        finish_vault_write(file, &temp, &target, last_modified).await
+    }
+
+    /// Decrypt the content behind `blob` into a temp file for
+    /// `relative_path`, so it is never held whole, and check it hashes to
+    /// `hash`. Returns what [`SyncEngine::create_vault_temp`] does, for
+    /// [`finish_vault_write`] to rename into place.
+    async fn stage_vault_content(&self, relative_path: &str, blob: [u8; 32], hash: [u8; 32]) -> Result<(tokio::fs::File, PathBuf, PathBuf)> {
+        let (mut file, temp, target) = self.create_vault_temp(relative_path, hash).await?;
+        let written = self.write_content(blob, &mut file).await;
+        if written.as_ref().ok() != Some(&hash) {
+            drop(file);
+            let _ = tokio::fs::remove_file(&temp).await;
+            written?;
+            return Err(anyhow!("content hash mismatch for {}", relative_path));
+        }
+        Ok((file, temp, target))
+    }
+
+    /// Check `relative_path` stays inside the vault, mark content hashing
+    /// to `hash` there as our own write, and create the temp file it is
+    /// written to. Returns the temp file, its path and the target path.
+    async fn create_vault_temp(&self, relative_path: &str, hash: [u8; 32]) -> Result<(tokio::fs::File, PathBuf, PathBuf)> {
+        let relative = Path::new(relative_path);
+        if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(anyhow!("refusing to write outside the vault: {}", relative_path));
+        }
+        let target = self.vault_path.join(relative);
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        self.applied.lock().await.insert(target.clone(), (hash, Instant::now()));
+
+        let mut temp_name = target.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(TEMP_SUFFIX);
+        let temp = target.with_file_name(temp_name);
+        let file = tokio::fs::File::create(&temp).await?;
+        Ok((file, temp, target))
+    }
+
+    async fn rename_vault_file(&self, from: &str, to: &str, hash: [u8; 32]) -> Result<()> {
+        for relative_path in [from, to] {
+            if !Path::new(relative_path).components().all(|c| matches!(c, Component::Normal(_))) {
+                return Err(anyhow!("refusing to move outside the vault: {}", relative_path));
+            }
+        }
+        let target = self.vault_path.join(to);
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        self.applied.lock().await.insert(target.clone(), (hash, Instant::now()));
+        tokio::fs::rename(self.vault_path.join(from), &target).await?;
+        Ok(())
+    }
+
+    async fn remove_vault_file(&self, relative_path: &str) -> Result<()> {
+        let relative = Path::new(relative_path);
+        if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(anyhow!("refusing to delete outside the vault: {}", relative_path));
+        }
+
+        match tokio::fs::remove_file(self.vault_path.join(relative)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// How long a file has to stay unchanged before it is synced.
+    pub async fn set_quiet_period(&self, quiet_period: Duration) {
+        self.changes.lock().await.set_quiet_period(quiet_period);
+    }
+
+    /// Switch to a new key derived from `passphrase` and re-encrypt
+    /// everything under it in the background. Earlier keys stay known, so
+    /// sync carries on while that runs. The new key parameters are published
+    /// on every remote; other devices have to unlock with the new passphrase
+    /// before they can read new blobs.
+    pub async fn rotate_key(self: &Arc<Self>, passphrase: String) -> Result<()> {
+        let kdf = KeyParams::load(&self.key_params_path)?
+            .map(|params| params.kdf)
+            .unwrap_or_default();
+        let ring = self.keys.lock().await.clone();
+        let passphrase = zeroize::Zeroizing::new(passphrase);
+        let (params, ring) =
+            tokio::task::spawn_blocking(move || KeyParams::rotate(&ring, &passphrase, kdf)).await??;
+        params.save(&self.key_params_path)?;
+
+        // Everything the earlier keys sealed, to remove once it is resealed.
+        let superseded = self.referenced_blobs().await;
+        let (epoch, key) = ring.current();
+        self.encryptor.add_epoch(epoch, key.as_bytes());
+        *self.keys.lock().await = ring;
+
+        let engine = self.clone();
+        tokio::spawn(async move {
+            let remotes = engine.remotes.read().await.clone();
+            for remote in &remotes {
+                if let Err(e) = engine.publish_key_params(remote).await {
+                    eprintln!("Publishing key parameters to {} failed: {}", remote.name(), e);
+                }
+            }
+            engine.reencrypt(epoch, &superseded).await;
+            let live = engine.referenced_blobs().await;
+            let stale = superseded.difference(&live).copied().collect();
+            if let Err(e) = engine.p2p.remove_blobs(&stale).await {
+                eprintln!("Removing blobs sealed under earlier keys failed: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Every blob the index refers to: the hash sequence of each file's
+    /// content and of each revision in its history, and the chunks they list.
+    async fn referenced_blobs(&self) -> HashSet<iroh::blobs::Hash> {
+        let (seqs, mut blobs): (Vec<[u8; 32]>, HashSet<iroh::blobs::Hash>) = {
+            let indexer = self.indexer.read().await;
+            let metas = indexer.metadata.values();
+            (
+                metas.clone().flat_map(|meta| meta.revisions().filter_map(|revision| revision.blob)).collect(),
+                metas.flat_map(|meta| meta.chunks.iter().map(|chunk| chunk.blob.into())).collect(),
+            )
+        };
+        for seq in seqs {
+            blobs.insert(seq.into());
+            // Missing for content never fetched, and not a sequence for
+            // entries indexed before chunking.
+            if let Ok(chunks) = self.p2p.read_hash_seq(seq.into()).await {
+                blobs.extend(chunks);
+            }
+        }
+        blobs
+    }
+
+    /// Re-seal the content of every file still under an older epoch and the
+    /// revisions in its history among `superseded` and, with remotes
+    /// configured, upload every file again under the new key, which has the
+    /// remotes delete what the old key sealed.
+    async fn reencrypt(&self, epoch: u32, superseded: &HashSet<iroh::blobs::Hash>) {
+        let files: Vec<FileMetadata> = self
+            .indexer
+            .read()
+            .await
+            .metadata
+            .values()
+            .filter(|meta| meta.blob.is_some() || meta.history.iter().any(|revision| revision.blob.is_some()))
+            .cloned()
+            .collect();
+
+        let total = files.len();
+        self.set_rotation_progress(Some(RotationProgress { epoch, done: 0, total })).await;
+        for (done, meta) in files.into_iter().enumerate() {
+            if let Err(e) = self.reencrypt_file(&meta, epoch, superseded).await {
+                eprintln!("Re-encrypting {} failed: {}", meta.path, e);
+            }
+            self.set_rotation_progress(Some(RotationProgress { epoch, done: done + 1, total })).await;
+        }
+        self.set_rotation_progress(None).await;
+    }
+
+    async fn reencrypt_file(&self, meta: &FileMetadata, epoch: u32, superseded: &HashSet<iroh::blobs::Hash>) -> Result<()> {
+        let _permit = self.uploads.acquire().await?;
+
+        if let Some(mut blob) = meta.blob.filter(|_| !meta.is_deleted()) {
+            if meta.chunks.iter().any(|chunk| chunk.epoch < epoch) {
+                let (resealed, chunks, _) = self
+                    .pipe_content(blob, |reader| store_chunks(&self.p2p, &self.indexer, &self.encryptor, reader))
+                    .await?;
+                self.indexer.write().await.set_blob(&meta.path, meta.hash, resealed, &chunks)?;
+                blob = resealed;
+            }
+            let remotes = self.remotes.read().await.clone();
+            for remote in &remotes {
+                // The content goes up under a new name; the old one is removed.
+                self.pipe_content(blob, |reader| remote.remote.push(vec![(meta.clone(), reader)], Vec::new())).await?;
+            }
+        }
+
+        // Revisions don't record their chunks' epochs; those the earlier
+        // keys sealed are sealed again.
+        for revision in &meta.history {
+            let Some(blob) = revision.blob.filter(|blob| superseded.contains(blob)) else {
+                continue;
+            };
+            let (resealed, chunks, _) = self
+                .pipe_content(blob, |reader| store_chunks(&self.p2p, &self.indexer, &self.encryptor, reader))
+                .await?;
+            self.indexer.write().await.set_blob(&meta.path, revision.hash, resealed, &chunks)?;
+        }
+        Ok(())
+    }
+
+    async fn set_rotation_progress(&self, rotation: Option<RotationProgress>) {
+        self.status.write().await.rotation = rotation;
+    }
+
+    pub async fn get_conflicts(&self) -> Vec<ConflictRecord> {
+        self.conflicts.read().await.clone()
+    }
+
+    pub async fn set_conflict_strategy(&self, strategy: ConflictStrategy) {
+        *self.conflict_strategy.write().await = strategy;
+    }
+
+    /// Whether a watcher event for `path` is just our own remote write.
+    async fn is_echo(&self, path: &Path, hash: &[u8; 32]) -> bool {
+        let mut applied = self.applied.lock().await;
+        applied.retain(|_, (_, at)| at.elapsed() < ECHO_WINDOW);
+        applied.get(path).is_some_and(|(expected, _)| expected == hash)
+    }
+
     async fn handle_p2p_event(&self, event: P2pEvent) {
         match event {
-            P2pEvent::PeerConnected(_) => {
+            P2pEvent::PeerConnected(peer) => {
+                if let Err(e) = self.indexer.write().await.add_device(&peer) {
+                    eprintln!("Failed to remember device {}: {}", peer, e);
+                }
                 let mut status = self.status.write().await;
                 status.peers_connected += 1;
             }
@@ -166,19 +1622,505 @@ impl SyncEngine {
                 let mut status = self.status.write().await;
                 status.peers_connected = status.peers_connected.saturating_sub(1);
             }
+            P2pEvent::RemoteChanges { peer, changes } => {
+                // Moves go first, before their tombstone deletes the source.
+                let mut moved = HashSet::new();
+                for meta in &changes {
+                    let Some(source) = move_source(meta, &changes) else {
+                        continue;
+                    };
+                    match self.apply_remote_move(meta, source).await {
+                        Ok(true) => {
+                            moved.insert(meta.path.clone());
+                            moved.insert(source.path.clone());
+                        }
+                        Ok(false) => {}
+                        Err(e) => eprintln!("Failed to move {} from {}: {}", meta.path, peer, e),
+                    }
+                }
+                for meta in changes.iter().filter(|meta| !moved.contains(&meta.path)) {
+                    if let Err(e) = self.apply_remote_change(meta, &peer).await {
+                        eprintln!("Failed to apply {} from {}: {}", meta.path, peer, e);
+                    }
+                }
+                let mut status = self.status.write().await;
+                status.last_sync = Some(Utc::now());
+            }
             _ => {}
         }
     }
 
     pub async fn get_status(&self) -> SyncStatus {
-        self.status.read().await.clone()
+        let mut status = self.status.read().await.clone();
+        for remote in self.remotes.read().await.iter() {
+            if let Some(until) = remote.remote.backend.limited_until() {
+                status.remote_limits.insert(remote.name().to_string(), until);
+            }
+        }
+        status
     }
 
     pub async fn get_recent_activity(&self) -> Vec<crate::engine::storage::FileMetadata> {
         let indexer = self.indexer.read().await;
-        let mut activity: Vec<_> = indexer.metadata.values().cloned().collect();
-        activity.sort_by(|a, b| b.last_modified.cmp(&a.last_modified));
+        let mut activity: Vec<_> = indexer
+            .metadata
+            .values()
+            .filter(|meta| !meta.is_deleted())
+            .cloned()
+            .collect();
+        activity.sort_by_key(|meta| std::cmp::Reverse(meta.last_modified));
         activity.truncate(10);
         activity
     }
 }
+
+fn is_markdown(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("md"))
+}
+
+fn is_temp_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(TEMP_SUFFIX))
+}
+
+fn mtime_secs(meta: &std::fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Split what `reader` yields into chunks, seal and import the ones not
+/// stored yet, and add the hash sequence listing them. Returns the
+/// sequence's hash, the chunks and the hash of the content read.
+async fn store_chunks<R: AsyncRead + Unpin>(
+    p2p: &P2pNode,
+    indexer: &RwLock<VaultIndexer>,
+    encryptor: &Encryptor,
+    reader: R,
+) -> Result<([u8; 32], Vec<Chunk>, [u8; 32])> {
+    let epoch = encryptor.current_epoch();
+    let mut chunks = Vec::new();
+    let mut content_hash = blake3::Hasher::new();
+    let mut pieces = ChunkReader::new(reader);
+    while let Some(piece) = pieces.next_chunk().await? {
+        content_hash.update(&piece);
+        let hash: [u8; 32] = blake3::hash(&piece).into();
+        let known = indexer.read().await.chunk_blob(&hash);
+        let blob = match known {
+            Some(chunk) if chunk.epoch == epoch && p2p.has_blob(chunk.blob.into()).await? => chunk.blob,
+            _ => {
+                let mut sealed = Vec::new();
+                encryptor.seal_stream(&mut &piece[..], &mut sealed, Binding::NONE, Some(&hash)).await?;
+                p2p.add_blob(sealed).await?.into()
+            }
+        };
+        chunks.push(Chunk {
+            hash,
+            blob,
+            size: piece.len() as u64,
+            epoch,
+        });
+    }
+
+    let blobs: Vec<iroh::blobs::Hash> = chunks.iter().map(|chunk| chunk.blob.into()).collect();
+    let seq = p2p.add_hash_seq(&blobs).await?;
+    Ok((seq.into(), chunks, content_hash.finalize().into()))
+}
+
+/// Run `produce`, writing into a pipe, alongside `consume`, reading from it.
+async fn pipe<T, P, PFut, C, CFut>(produce: P, consume: C) -> Result<T>
+where
+    P: FnOnce(DuplexStream) -> PFut,
+    PFut: Future<Output = Result<()>>,
+    C: FnOnce(DuplexStream) -> CFut,
+    CFut: Future<Output = Result<T>>,
+{
+    let (writer, reader) = tokio::io::duplex(STREAM_BUFFER_LEN);
+    // `produce` owns the writer, so the pipe ends when it returns.
+    let (produced, consumed) = tokio::join!(produce(writer), consume(reader));
+    // A failed write ends the pipe early, so what was consumed is partial.
+    let consumed = consumed?;
+    produced?;
+    Ok(consumed)
+}
+
+/// Where content to chunk and upload is read from.
+enum ContentSource {
+    Bytes(Vec<u8>),
+    /// Opened when it is needed, so large files are never held whole.
+    File(PathBuf),
+}
+
+impl ContentSource {
+    async fn reader(&self) -> Result<Box<dyn AsyncRead + Unpin + Send + '_>> {
+        Ok(match self {
+            ContentSource::Bytes(bytes) => Box::new(&bytes[..]),
+            ContentSource::File(path) => Box::new(tokio::fs::File::open(path).await?),
+        })
+    }
+}
+
+/// Hash and size of the file at `path`, read a buffer at a time.
+async fn hash_file(path: &Path) -> Result<([u8; 32], u64)> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0u8; STREAM_BUFFER_LEN];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        size += read as u64;
+    }
+    Ok((hasher.finalize().into(), size))
+}
+
+/// Flush a temp file from [`SyncEngine::create_vault_temp`] and rename it
+/// over `target`.
+async fn finish_vault_write(mut file: tokio::fs::File, temp: &Path, target: &Path, last_modified: u64) -> Result<()> {
+    file.flush().await?;
+    file.sync_all().await?;
+    // Keep the peer's mtime so the startup scan's size/mtime check holds.
+    let modified = std::time::UNIX_EPOCH + Duration::from_secs(last_modified);
+    file.into_std().await.set_modified(modified)?;
+    tokio::fs::rename(temp, target).await?;
+    Ok(())
+}
+
+/// Every regular, non-ignored file under `dir` with its size and mtime.
+/// Ignored folders aren't descended into.
+fn list_vault_files(vault_path: &Path, dir: &Path, rules: &IgnoreRules) -> Result<Vec<(PathBuf, u64, u64)>> {
+    let mut files = Vec::new();
+    let walker = walkdir::WalkDir::new(dir).into_iter().filter_entry(|entry| {
+        entry
+            .path()
+            .strip_prefix(vault_path)
+            .is_ok_and(|relative| !rules.is_ignored(relative, entry.file_type().is_dir()))
+    });
+    for entry in walker {
+        let entry = entry?;
+        if !entry.file_type().is_file() || is_temp_file(entry.path()) {
+            continue;
+        }
+        let meta = entry.metadata()?;
+        files.push((entry.into_path(), meta.len(), mtime_secs(&meta)));
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{store_chunks, PushQueue, RemoteChange, RemoteSync, SyncEngine};
+    use crate::engine::keys::{KdfParams, KeyParams};
+    use crate::engine::remote::memory::MemoryBackend;
+    use crate::engine::remote::{Remote, RemoteBackend};
+    use crate::engine::storage::{FileMetadata, VaultIndexer};
+    use crate::engine::version::{Causality, VersionVector};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("oversync-{}-{}", name, uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// An engine whose watcher never gets to process a change, so tests
+    /// decide what is indexed when.
+    async fn engine() -> Arc<SyncEngine> {
+        let engine = SyncEngine::new(temp_dir("vault"), temp_dir("data"), [7u8; 32].into(), None, Default::default())
+            .await
+            .unwrap();
+        engine.set_quiet_period(Duration::from_secs(3600)).await;
+        engine
+    }
+
+    /// Write `content` to `path` in the vault and index it.
+    async fn edit(engine: &SyncEngine, path: &str, content: &[u8]) -> FileMetadata {
+        let full = engine.vault_path.join(path);
+        tokio::fs::write(&full, content).await.unwrap();
+        engine.process_file_change(full).await.unwrap();
+        engine.indexer.read().await.get_metadata(path).unwrap().clone()
+    }
+
+    /// `path`'s entry once its content has been stored.
+    async fn stored(engine: &SyncEngine, path: &str) -> FileMetadata {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let meta = engine.indexer.read().await.get_metadata(path).cloned();
+                if let Some(meta) = meta.filter(|meta| meta.is_chunked()) {
+                    return meta;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    /// A peer's change to `path`, its content stored as if fetched.
+    async fn change(engine: &SyncEngine, path: &str, content: &[u8], version: VersionVector) -> FileMetadata {
+        let (blob, chunks, hash) = store_chunks(&engine.p2p, &engine.indexer, &engine.encryptor, content).await.unwrap();
+        FileMetadata {
+            path: path.to_string(),
+            size: content.len() as u64,
+            hash,
+            last_modified: 1_700_000_000,
+            blob: Some(blob),
+            chunks,
+            version,
+            history: Vec::new(),
+            tombstone: None,
+            moved_from: None,
+        }
+    }
+
+    /// Sync `engine` through `backend`, pushing and pulling only when the
+    /// test says so.
+    async fn attach(engine: &SyncEngine, backend: Arc<dyn RemoteBackend>) -> Arc<RemoteSync> {
+        let remote = Arc::new(RemoteSync::open(backend, &engine.data_dir, &*engine.indexer.read().await).unwrap());
+        engine.remotes.write().await.push(remote.clone());
+        remote
+    }
+
+    async fn read(backend: &dyn RemoteBackend, path: &str) -> Option<Vec<u8>> {
+        let (_, index) = backend.list().await.unwrap();
+        let entry = index.files.get(path)?;
+        let mut content = Vec::new();
+        backend.get(entry, &mut content).await.unwrap();
+        Some(content)
+    }
+
+    fn following(version: &VersionVector, device: &str) -> VersionVector {
+        let mut version = version.clone();
+        version.increment(device);
+        version
+    }
+
+    #[tokio::test]
+    async fn remote_changes_are_applied_by_version() {
+        let engine = engine().await;
+        let path = engine.vault_path.join("notes.txt");
+        let ours = edit(&engine, "notes.txt", b"ours").await;
+
+        // Before ours: skipped.
+        let older = change(&engine, "notes.txt", b"older", VersionVector::default()).await;
+        engine.apply_remote_change(&older, "peer").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"ours");
+
+        // After ours: written.
+        let newer = change(&engine, "notes.txt", b"theirs", following(&ours.version, "peer")).await;
+        engine.apply_remote_change(&newer, "peer").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"theirs");
+        assert_eq!(engine.indexer.read().await.get_metadata("notes.txt").unwrap().version, newer.version);
+
+        // Concurrent with an edit made since: both are kept.
+        let ours = edit(&engine, "notes.txt", b"edited").await;
+        let concurrent = change(&engine, "notes.txt", b"concurrent", following(&newer.version, "peer")).await;
+        engine.apply_remote_change(&concurrent, "peer").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"edited");
+        let conflicts = engine.get_conflicts().await;
+        let copy = conflicts[0].conflict_copy.as_ref().unwrap();
+        assert_eq!(std::fs::read(engine.vault_path.join(copy)).unwrap(), b"concurrent");
+        let local = engine.indexer.read().await.get_metadata("notes.txt").unwrap().clone();
+        assert_eq!(local.version.compare(&concurrent.version), Causality::After);
+        assert_eq!(local.version.compare(&ours.version), Causality::After);
+
+        // The same edit made elsewhere: only the versions are merged.
+        let same = change(&engine, "notes.txt", b"edited", following(&VersionVector::default(), "other")).await;
+        engine.apply_remote_change(&same, "other").await.unwrap();
+        let local = engine.indexer.read().await.get_metadata("notes.txt").unwrap().clone();
+        assert_eq!(local.version.compare(&same.version), Causality::After);
+
+        // An edit not indexed yet isn't overwritten.
+        std::fs::write(&path, b"unindexed").unwrap();
+        let next = change(&engine, "notes.txt", b"next", following(&local.version, "peer")).await;
+        assert!(engine.apply_remote_change(&next, "peer").await.is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"unindexed");
+    }
+
+    #[tokio::test]
+    async fn edits_contested_on_a_remote_are_pushed_once_settled() {
+        let engine = engine().await;
+        let backend = Arc::new(MemoryBackend::new("memory"));
+        let remote = attach(&engine, backend.clone()).await;
+
+        let mut rival = VaultIndexer::new();
+        rival.set_device_id("rival".to_string());
+        rival.update_file("notes.txt".to_string(), b"theirs", 0).unwrap();
+        let theirs = rival.get_metadata("notes.txt").unwrap().clone();
+        Remote::new(backend.clone()).push(vec![(theirs, &b"theirs"[..])], Vec::new()).await.unwrap();
+
+        // Concurrent with theirs, so it stays queued until pulled.
+        edit(&engine, "notes.txt", b"ours").await;
+        engine.push_to_remote(&remote).await.unwrap();
+        assert_eq!(read(&*backend, "notes.txt").await.unwrap(), b"theirs");
+        assert!(remote.queue.lock().await.pending.contains_key("notes.txt"));
+
+        engine.pull_from_remote(&remote).await.unwrap();
+        let conflicts = engine.get_conflicts().await;
+        let copy = conflicts[0].conflict_copy.clone().unwrap();
+        engine.push_to_remote(&remote).await.unwrap();
+        assert_eq!(read(&*backend, "notes.txt").await.unwrap(), b"ours");
+        assert_eq!(read(&*backend, &copy).await.unwrap(), b"theirs");
+        assert!(remote.queue.lock().await.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pulls_keep_remote_times_and_push_over_unversioned_entries() {
+        let engine = engine().await;
+        let backend = Arc::new(MemoryBackend::new("memory"));
+        let remote = attach(&engine, backend.clone()).await;
+
+        let mut rival = VaultIndexer::new();
+        rival.set_device_id("rival".to_string());
+        rival.update_file("theirs.txt".to_string(), b"theirs", 1_700_000_000).unwrap();
+        let theirs = rival.get_metadata("theirs.txt").unwrap().clone();
+        Remote::new(backend.clone()).push(vec![(theirs, &b"theirs"[..])], Vec::new()).await.unwrap();
+        engine.pull_from_remote(&remote).await.unwrap();
+        assert_eq!(engine.indexer.read().await.get_metadata("theirs.txt").unwrap().last_modified, 1_700_000_000);
+
+        // As if pushed before versions were recorded: ours isn't overwritten
+        // but pushed over it.
+        edit(&engine, "ours.txt", b"ours").await;
+        remote.queue.lock().await.pending.clear();
+        let legacy = backend.put(&mut &b"legacy"[..], 6).await.unwrap();
+        let (version, mut index) = backend.list().await.unwrap();
+        index.files.insert("ours.txt".to_string(), legacy);
+        assert!(backend.swap_manifest(&version, &index, "legacy").await.unwrap());
+        engine.pull_from_remote(&remote).await.unwrap();
+        assert_eq!(std::fs::read(engine.vault_path.join("ours.txt")).unwrap(), b"ours");
+        engine.push_to_remote(&remote).await.unwrap();
+        assert_eq!(read(&*backend, "ours.txt").await.unwrap(), b"ours");
+    }
+
+    #[tokio::test]
+    async fn added_remotes_get_the_vault_and_changes_pulled_from_others() {
+        let engine = engine().await;
+        edit(&engine, "before.txt", b"before").await;
+        let first = Arc::new(MemoryBackend::new("first"));
+        let first_remote = attach(&engine, first.clone()).await;
+
+        // The sync loop pushes and pulls as soon as a remote is added.
+        let second = Arc::new(MemoryBackend::new("second"));
+        engine.add_remote(second.clone()).await.unwrap();
+        assert!(engine.add_remote(Arc::new(MemoryBackend::new("second"))).await.is_err());
+        let second_remote = engine.remotes.read().await[1].clone();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while second_remote.pulled.lock().await.is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(read(&*second, "before.txt").await.unwrap(), b"before");
+
+        let mut rival = VaultIndexer::new();
+        rival.set_device_id("rival".to_string());
+        rival.update_file("theirs.txt".to_string(), b"theirs", 0).unwrap();
+        let theirs = rival.get_metadata("theirs.txt").unwrap().clone();
+        Remote::new(first.clone()).push(vec![(theirs, &b"theirs"[..])], Vec::new()).await.unwrap();
+
+        engine.pull_from_remote(&first_remote).await.unwrap();
+        assert_eq!(std::fs::read(engine.vault_path.join("theirs.txt")).unwrap(), b"theirs");
+        assert!(!first_remote.queue.lock().await.pending.contains_key("theirs.txt"));
+        engine.push_to_remote(&second_remote).await.unwrap();
+        assert_eq!(read(&*second, "theirs.txt").await.unwrap(), b"theirs");
+    }
+
+    #[test]
+    fn push_queues_survive_restarts_and_bad_files() {
+        let path = temp_dir("queue").join("memory_queue.json");
+        assert!(PushQueue::load(&path).is_none());
+
+        std::fs::write(&path, br#"{"pending":{"a.md":"upload"},"pushing":{"b.md":"delete"}}"#).unwrap();
+        let queue = PushQueue::load(&path).unwrap();
+        assert_eq!(queue.pending.get("b.md"), Some(&RemoteChange::Delete));
+        assert!(queue.pushing.is_empty());
+
+        std::fs::write(&path, b"{\"pending\":").unwrap();
+        assert!(PushQueue::load(&path).unwrap().pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn scans_queue_what_changed_while_closed() {
+        use notify::EventKind;
+
+        let engine = engine().await;
+        edit(&engine, "unchanged.md", b"same").await;
+        edit(&engine, "modified.md", b"before").await;
+        edit(&engine, "removed.md", b"gone").await;
+        std::fs::write(engine.vault_path.join("modified.md"), b"after, longer").unwrap();
+        std::fs::remove_file(engine.vault_path.join("removed.md")).unwrap();
+        std::fs::write(engine.vault_path.join("new.md"), b"new").unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        engine.scan_vault(tx).await.unwrap();
+        let mut events = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            let path = event.paths[0].strip_prefix(&engine.vault_path).unwrap().to_string_lossy().to_string();
+            let kind = match event.kind {
+                EventKind::Create(_) => "create",
+                EventKind::Modify(_) => "modify",
+                EventKind::Remove(_) => "remove",
+                _ => "other",
+            };
+            events.push((path, kind));
+        }
+        events.sort();
+        assert_eq!(
+            events,
+            vec![
+                ("modified.md".to_string(), "modify"),
+                ("new.md".to_string(), "create"),
+                ("removed.md".to_string(), "remove"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rotation_reseals_everything_and_removes_what_the_old_key_sealed() {
+        let engine = engine().await;
+        let cheap = KdfParams {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        };
+        KeyParams::generate("old", cheap).unwrap().0.save(&engine.key_params_path).unwrap();
+        let backend = Arc::new(MemoryBackend::new("memory"));
+        let remote = attach(&engine, backend.clone()).await;
+
+        edit(&engine, "note.md", b"before the rotation").await;
+        let old = stored(&engine, "note.md").await;
+        edit(&engine, "note.md", b"edited before the rotation").await;
+        let edited = stored(&engine, "note.md").await;
+        engine.push_to_remote(&remote).await.unwrap();
+
+        engine.rotate_key("new".to_string()).await.unwrap();
+        let old_chunk = old.chunks[0].blob.into();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while engine.p2p.has_blob(old_chunk).await.unwrap() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let meta = engine.indexer.read().await.get_metadata("note.md").unwrap().clone();
+        assert!(meta.chunks.iter().all(|chunk| chunk.epoch == 1));
+        assert!(!engine.p2p.has_blob(edited.chunks[0].blob.into()).await.unwrap());
+        assert_eq!(engine.read_content(meta.blob.unwrap()).await.unwrap(), b"edited before the rotation");
+        let revision = meta.history.iter().find(|revision| revision.hash == old.hash).unwrap();
+        assert_ne!(revision.blob, old.blob);
+        assert_eq!(engine.read_content(revision.blob.unwrap()).await.unwrap(), b"before the rotation");
+
+        assert_eq!(backend.key_params().await.unwrap().unwrap().epoch, 1);
+        assert_eq!(read(&*backend, "note.md").await.unwrap(), b"edited before the rotation");
+    }
+}
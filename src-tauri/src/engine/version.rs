@@ -0,0 +1,99 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+
+/// How two versions of the same file relate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    Equal,
+    /// `self` happened before `other`; `other` can fast-forward over it.
+    Before,
+    /// `self` already includes everything in `other`.
+    After,
+    /// Both sides edited without seeing each other's change.
+    Concurrent,
+}
+
+/// Per-file edit counters keyed by device id.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+impl VersionVector {
+    pub fn get(&self, device: &str) -> u64 {
+        self.0.get(device).copied().unwrap_or(0)
+    }
+
+    pub fn increment(&mut self, device: &str) {
+        *self.0.entry(device.to_string()).or_insert(0) += 1;
+    }
+
+    /// Every device that has edited this file.
+    pub fn devices(&self) -> impl Iterator<Item = &String> {
+        self.0.keys()
+    }
+
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (device, counter) in &other.0 {
+            let entry = self.0.entry(device.clone()).or_insert(0);
+            *entry = (*entry).max(*counter);
+        }
+    }
+
+    pub fn merged(&self, other: &VersionVector) -> VersionVector {
+        let mut merged = self.clone();
+        merged.merge(other);
+        merged
+    }
+
+    pub fn compare(&self, other: &VersionVector) -> Causality {
+        let mut ordering = Ordering::Equal;
+        for device in self.0.keys().chain(other.0.keys()) {
+            let step = self.get(device).cmp(&other.get(device));
+            ordering = match (ordering, step) {
+                (current, Ordering::Equal) => current,
+                (Ordering::Equal, step) => step,
+                (current, step) if current == step => current,
+                _ => return Causality::Concurrent,
+            };
+        }
+
+        match ordering {
+            Ordering::Equal => Causality::Equal,
+            Ordering::Less => Causality::Before,
+            Ordering::Greater => Causality::After,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Causality, VersionVector};
+
+    #[test]
+    fn compare_orders_causal_edits() {
+        let mut a = VersionVector::default();
+        a.increment("laptop");
+        let mut b = a.clone();
+        b.increment("phone");
+
+        assert_eq!(a.compare(&a), Causality::Equal);
+        assert_eq!(a.compare(&b), Causality::Before);
+        assert_eq!(b.compare(&a), Causality::After);
+    }
+
+    #[test]
+    fn compare_detects_concurrent_edits() {
+        let mut base = VersionVector::default();
+        base.increment("laptop");
+        let mut laptop = base.clone();
+        laptop.increment("laptop");
+        let mut phone = base.clone();
+        phone.increment("phone");
+
+        assert_eq!(laptop.compare(&phone), Causality::Concurrent);
+
+        let merged = laptop.merged(&phone);
+        assert_eq!(merged.compare(&laptop), Causality::After);
+        assert_eq!(merged.compare(&phone), Causality::After);
+    }
+}
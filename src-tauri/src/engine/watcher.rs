@@ -8,10 +8,12 @@ pub struct VaultWatcher {
 }
 
 impl VaultWatcher {
-    pub fn new(path: &Path, tx: mpsc::UnboundedSender<Event>) -> Result<Self> {
+    /// Forward events for `path` into `tx`. The callback runs on notify's
+    /// own thread, which blocks while the channel is full.
+    pub fn new(path: &Path, tx: mpsc::Sender<Event>) -> Result<Self> {
         let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
             if let Ok(event) = res {
-                let _ = tx.send(event);
+                let _ = tx.blocking_send(event);
             }
         })?;
 
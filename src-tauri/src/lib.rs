@@ -3,8 +3,14 @@ pub mod engine;
 use std::path::PathBuf;
 use std::sync::Arc;
 use crate::engine::{SyncEngine, GithubConfig, SyncStatus};
+use crate::engine::conflict::{ConflictRecord, ConflictStrategy};
+use crate::engine::encryption::EncryptionMode;
+use crate::engine::github::{GitHubStorage, UnmigratedStore};
+use crate::engine::keys::{self, KeyParams, KEY_PARAMS_FILE};
+use crate::engine::obsidian_config::ConfigSyncSettings;
 use tokio::sync::RwLock;
 use tauri::Manager;
+use zeroize::Zeroizing;
 
 pub struct AppState {
     pub sync_engine: RwLock<Option<Arc<SyncEngine>>>,
@@ -17,20 +23,39 @@ async fn initialize_sync(
     vault_path: String,
     github_config: Option<GithubConfig>,
     encryption_key: String,
+    encryption_mode: Option<EncryptionMode>,
+    key_params: Option<KeyParams>,
 ) -> Result<(), String> {
-    let mut key_bytes = [0u8; 32];
-    let key_src = encryption_key.as_bytes();
-    let len = key_src.len().min(32);
-    key_bytes[..len].copy_from_slice(&key_src[..len]);
-
+    let passphrase = Zeroizing::new(encryption_key);
     let app_data_dir = app.path().app_data_dir().map_err(|e: tauri::Error| e.to_string())?;
-    let p2p_data_dir = app_data_dir.join("p2p_data");
+
+    // A device joining through GitHub finds the vault's key parameters there.
+    let published = match &github_config {
+        Some(config) => match GitHubStorage::published_key_params(config.clone()).await {
+            Ok(params) => params,
+            Err(e) if e.is::<UnmigratedStore>() => return Err(e.to_string()),
+            Err(e) => {
+                eprintln!("Failed to read the key parameters published on GitHub: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    let key_params: Vec<KeyParams> = key_params.into_iter().chain(published).collect();
+
+    // Argon2id is deliberately slow; keep it off the async runtime.
+    let key_dir = app_data_dir.clone();
+    let key = tokio::task::spawn_blocking(move || keys::unlock(&key_dir, &passphrase, key_params))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
 
     let engine = SyncEngine::new(
         PathBuf::from(vault_path),
-        p2p_data_dir,
-        key_bytes,
+        app_data_dir,
+        key,
         github_config,
+        encryption_mode.unwrap_or_default(),
     ).await.map_err(|e| e.to_string())?;
 
     let mut sync_engine = state.sync_engine.write().await;
@@ -79,6 +104,96 @@ async fn get_recent_activity(state: tauri::State<'_, AppState>) -> Result<Vec<cr
     }
 }
 
+#[tauri::command]
+async fn get_conflicts(state: tauri::State<'_, AppState>) -> Result<Vec<ConflictRecord>, String> {
+    let engine = state.sync_engine.read().await;
+    if let Some(engine) = engine.as_ref() {
+        Ok(engine.get_conflicts().await)
+    } else {
+        Err("Sync engine not initialized".to_string())
+    }
+}
+
+#[tauri::command]
+async fn set_conflict_strategy(state: tauri::State<'_, AppState>, strategy: ConflictStrategy) -> Result<(), String> {
+    let engine = state.sync_engine.read().await;
+    if let Some(engine) = engine.as_ref() {
+        engine.set_conflict_strategy(strategy).await;
+        Ok(())
+    } else {
+        Err("Sync engine not initialized".to_string())
+    }
+}
+
+#[tauri::command]
+async fn set_quiet_period(state: tauri::State<'_, AppState>, millis: u64) -> Result<(), String> {
+    let engine = state.sync_engine.read().await;
+    if let Some(engine) = engine.as_ref() {
+        engine.set_quiet_period(std::time::Duration::from_millis(millis)).await;
+        Ok(())
+    } else {
+        Err("Sync engine not initialized".to_string())
+    }
+}
+
+#[tauri::command]
+async fn get_ignore_overrides(state: tauri::State<'_, AppState>) -> Result<Vec<String>, String> {
+    let engine = state.sync_engine.read().await;
+    if let Some(engine) = engine.as_ref() {
+        engine.ignore_overrides().await.map_err(|e| e.to_string())
+    } else {
+        Err("Sync engine not initialized".to_string())
+    }
+}
+
+#[tauri::command]
+async fn set_ignore_overrides(state: tauri::State<'_, AppState>, rules: Vec<String>) -> Result<(), String> {
+    let engine = state.sync_engine.read().await;
+    if let Some(engine) = engine.as_ref() {
+        engine.set_ignore_overrides(rules).await.map_err(|e| e.to_string())
+    } else {
+        Err("Sync engine not initialized".to_string())
+    }
+}
+
+#[tauri::command]
+async fn get_config_sync(state: tauri::State<'_, AppState>) -> Result<ConfigSyncSettings, String> {
+    let engine = state.sync_engine.read().await;
+    if let Some(engine) = engine.as_ref() {
+        Ok(engine.config_sync().await)
+    } else {
+        Err("Sync engine not initialized".to_string())
+    }
+}
+
+#[tauri::command]
+async fn set_config_sync(state: tauri::State<'_, AppState>, settings: ConfigSyncSettings) -> Result<(), String> {
+    let engine = state.sync_engine.read().await;
+    if let Some(engine) = engine.as_ref() {
+        engine.set_config_sync(settings).await.map_err(|e| e.to_string())
+    } else {
+        Err("Sync engine not initialized".to_string())
+    }
+}
+
+#[tauri::command]
+async fn rotate_key(state: tauri::State<'_, AppState>, new_passphrase: String) -> Result<(), String> {
+    let engine = state.sync_engine.read().await;
+    if let Some(engine) = engine.as_ref() {
+        engine.rotate_key(new_passphrase).await.map_err(|e| e.to_string())
+    } else {
+        Err("Sync engine not initialized".to_string())
+    }
+}
+
+/// The vault's salt and KDF settings, to pass to `initialize_sync` on a
+/// device joining the vault.
+#[tauri::command]
+async fn get_key_params(app: tauri::AppHandle) -> Result<Option<KeyParams>, String> {
+    let app_data_dir = app.path().app_data_dir().map_err(|e: tauri::Error| e.to_string())?;
+    KeyParams::load(&app_data_dir.join(KEY_PARAMS_FILE)).map_err(|e| e.to_string())
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     let state = AppState {
@@ -116,7 +231,16 @@ pub fn run() {
             get_sync_status,
             generate_p2p_ticket,
             connect_peer,
-            get_recent_activity
+            get_recent_activity,
+            get_conflicts,
+            set_conflict_strategy,
+            set_quiet_period,
+            get_ignore_overrides,
+            set_ignore_overrides,
+            get_config_sync,
+            set_config_sync,
+            get_key_params,
+            rotate_key
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
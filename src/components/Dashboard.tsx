@@ -7,6 +7,8 @@ interface SyncStatus {
   is_syncing: boolean;
   last_sync: string | null;
   peers_connected: number;
+  scan: { scanned: number; total: number } | null;
+  remote_limits: Record<string, string>;
 }
 
 interface FileMetadata {
@@ -40,6 +42,8 @@ export const Dashboard = () => {
     }
   };
 
+  const limits = Object.entries(status?.remote_limits ?? {});
+
   const formatTime = (timestamp: number) => {
     const date = new Date(timestamp);
     return date.toLocaleTimeString([], { hour: '2-digit', minute: '2-digit' });
@@ -62,7 +66,20 @@ export const Dashboard = () => {
             label={status ? `${status.peers_connected} Peers` : 'P2P Offline'} 
             active={(status?.peers_connected ?? 0) > 0} 
           />
-          <StatusBadge icon={<Cloud size={16} />} label="GitHub Connected" active={true} />
+          {limits.length > 0 ? limits.map(([remote, until]) => (
+            <StatusBadge 
+              key={remote}
+              icon={<Cloud size={16} />} 
+              label={`${remote} rate limited until ${new Date(until).toLocaleTimeString([], { hour: '2-digit', minute: '2-digit' })}`} 
+              active={false} 
+            />
+          )) : (
+            <StatusBadge 
+              icon={<Cloud size={16} />} 
+              label="Remotes Connected" 
+              active={true} 
+            />
+          )}
         </div>
       </header>
 